@@ -0,0 +1,47 @@
+// Measures how many instructions per second the interpreter runs.
+//
+//   $ cargo bench --bench interpreter
+
+extern crate johnny_eight;
+
+use johnny_eight::vm::VM;
+
+use std::io::Cursor;
+use std::time::Instant;
+
+const FRAMES: usize = 1_000;
+const INSTRUCTIONS_PER_FRAME: u64 = 100_000;
+
+// A busy loop of arithmetic, skips and jumps, with no draws.
+const ROM: [u8; 16] = [
+    0x60, 0x00, // LD V0, 0
+    0x70, 0x01, // ADD V0, 1
+    0x81, 0x04, // ADD V1, V0
+    0x82, 0x13, // XOR V2, V1
+    0xA3, 0x00, // LD I, 0x300
+    0x30, 0xFF, // SE V0, 0xFF
+    0x12, 0x02, // JP 0x202
+    0x12, 0x00, // JP 0x200
+];
+
+fn run(decode_cache: bool) -> f64 {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(INSTRUCTIONS_PER_FRAME)
+        .set_decode_cache(decode_cache)
+        .load_rom(&mut Cursor::new(&ROM[..]))
+        .unwrap();
+
+    let start = Instant::now();
+    vm.run_for(FRAMES).unwrap();
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+    (FRAMES as u64 * INSTRUCTIONS_PER_FRAME) as f64 / seconds
+}
+
+fn main() {
+    for &(name, decode_cache) in &[("without decode cache", false), ("with decode cache", true)] {
+        let rate = run(decode_cache);
+        println!("{:>20}: {:>6.1}M instructions/sec", name, rate / 1e6);
+    }
+}
@@ -0,0 +1,24 @@
+// Sound output.
+//
+// The VM beeps while the sound timer is above zero. An `AudioSink` is told
+// when the beep starts and stops, and gets the XO-CHIP audio pattern so it
+// can play the right waveform.
+
+pub trait AudioSink {
+    /// The sound timer went above zero. `pattern` holds 128 1-bit samples
+    /// that loop at `rate` samples per second.
+    fn start(&mut self, pattern: &[u8], rate: f64);
+
+    /// The sound timer reached zero.
+    fn stop(&mut self);
+}
+
+/// Plays nothing, for runs without anyone listening.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct Silence;
+
+impl AudioSink for Silence {
+    fn start(&mut self, _pattern: &[u8], _rate: f64) {}
+
+    fn stop(&mut self) {}
+}
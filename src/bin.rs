@@ -1,101 +1,297 @@
 extern crate rand;
+#[cfg(feature = "sdl")]
 extern crate sdl2;
 extern crate johnny_eight;
-#[macro_use]
 extern crate env_logger;
 
-use johnny_eight::display::Display;
-use johnny_eight::vm::VM;
-use johnny_eight::specs;
-use johnny_eight::keypad::Key;
+mod tty;
+#[cfg(feature = "sdl")]
+mod window;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use johnny_eight::display::Palette;
+use johnny_eight::image::{GifRecorder, ImageFormat, Recording, Screenshot};
+use johnny_eight::vm::{VmBuilder, VmConfig, UnknownOpcodePolicy};
+use johnny_eight::specs;
+use johnny_eight::quirks::{self, Quirks};
+use johnny_eight::audio::Silence;
+use johnny_eight::clock::ScaledClock;
+use johnny_eight::timing::Timing;
+use johnny_eight::vm::{VM, VmError};
 
 use std::fs::File;
+use std::io::{BufWriter, Read};
 use std::env;
 use std::process::exit;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 fn main() {
     env_logger::init().unwrap();
 
-    let rom_path = match env::args().nth(1) {
+    let mut rom_path = None;
+    let mut quirks = Quirks::default();
+    let mut ram_size = specs::RAM_SIZE;
+    let mut unknown_opcode_policy = UnknownOpcodePolicy::Halt;
+    let mut seed = None;
+    let mut clock = ScaledClock::new(1.0).unwrap();
+    let mut timing = Timing::Fixed;
+    let mut machine_code = false;
+    let mut in_terminal = false;
+    let mut screenshot = Screenshot::default();
+    let mut screenshot_after = None;
+    let mut recording = Recording::default();
+    let mut record_for = None;
+    let mut layout = VmConfig::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                if name == "xochip" {
+                    ram_size = specs::XO_RAM_SIZE;
+                }
+                quirks = match quirks::preset(&name) {
+                    Some(preset) => preset,
+                    None => {
+                        println!("Unknown quirks {:?}, use one of: vip, chip48, schip, xochip",
+                                 name);
+                        exit(1);
+                    }
+                };
+            }
+            "--seed" => {
+                seed = match args.next().and_then(|value| value.parse::<u64>().ok()) {
+                    Some(value) => Some(value),
+                    None => {
+                        println!("The seed must be a positive number");
+                        exit(1);
+                    }
+                };
+            }
+            "--speed" => {
+                clock = match args.next()
+                    .and_then(|value| value.parse::<f64>().ok())
+                    .and_then(ScaledClock::new) {
+                    Some(clock) => clock,
+                    None => {
+                        println!("The speed must be a positive number, e.g. 2 or 0.5");
+                        exit(1);
+                    }
+                };
+            }
+            "--timing" => {
+                timing = match args.next().unwrap_or_default().as_ref() {
+                    "fixed" => Timing::Fixed,
+                    "vip" => Timing::Vip,
+                    name => {
+                        println!("Unknown timing {:?}, use one of: fixed, vip", name);
+                        exit(1);
+                    }
+                };
+            }
+            "--layout" => {
+                layout = match args.next().unwrap_or_default().as_ref() {
+                    "chip8" => VmConfig::default(),
+                    "eti660" => VmConfig::eti_660(),
+                    "hires" => VmConfig::hires_chip8(),
+                    name => {
+                        println!("Unknown layout {:?}, use one of: chip8, eti660, hires", name);
+                        exit(1);
+                    }
+                };
+            }
+            "--screenshot-format" => {
+                let name = args.next().unwrap_or_default();
+                screenshot.format = match ImageFormat::parse(&name) {
+                    Some(format) => format,
+                    None => {
+                        println!("Unknown image format {:?}, use one of: png, ppm", name);
+                        exit(1);
+                    }
+                };
+            }
+            "--screenshot-scale" => {
+                screenshot.scale = match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(value) if value > 0 => value,
+                    _ => {
+                        println!("The scale must be a positive number, e.g. 1 or 10");
+                        exit(1);
+                    }
+                };
+            }
+            "--palette" => {
+                screenshot.palette = match args.next().and_then(|value| Palette::parse(&value)) {
+                    Some(palette) => palette,
+                    None => {
+                        println!("The palette must be 2 or 4 hex colors, e.g. 000000,ffffff");
+                        exit(1);
+                    }
+                };
+                recording.palette = screenshot.palette;
+            }
+            "--screenshot-after" => {
+                screenshot_after = match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(value) => Some(value),
+                    None => {
+                        println!("The number of frames must be a positive number");
+                        exit(1);
+                    }
+                };
+            }
+            "--gif-scale" => {
+                recording.scale = match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(value) if value > 0 => value,
+                    _ => {
+                        println!("The scale must be a positive number, e.g. 1 or 4");
+                        exit(1);
+                    }
+                };
+            }
+            "--gif-fps" => {
+                recording.frame_step = match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(fps) if fps > 0 && 60 % fps == 0 => 60 / fps,
+                    _ => {
+                        println!("The frame rate must divide 60, e.g. 60, 30 or 20");
+                        exit(1);
+                    }
+                };
+            }
+            "--record" => {
+                record_for = match args.next().and_then(|value| value.parse::<usize>().ok()) {
+                    Some(value) => Some(value),
+                    None => {
+                        println!("The number of seconds must be a positive number");
+                        exit(1);
+                    }
+                };
+            }
+            "--skip-unknown" => unknown_opcode_policy = UnknownOpcodePolicy::Skip,
+            "--machine-code" => machine_code = true,
+            "--terminal" => in_terminal = true,
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    let rom_path = match rom_path {
         Some(path) => path,
         None => {
             println!("You must provide a path to the ROM file");
             exit(1);
         }
     };
-    let mut rom = File::open(rom_path).unwrap();
+    let captures = Captures::new(&rom_path, screenshot, recording);
+    let mut rom = vec![];
+    if let Err(err) = File::open(&rom_path).and_then(|mut file| file.read_to_end(&mut rom)) {
+        println!("{}", err);
+        exit(1);
+    }
 
-    // Window initialization
-    let ctx = sdl2::init().unwrap();
-    let video_ctx = ctx.video().unwrap();
-    let width = (specs::DISPLAY_WIDTH * specs::DISPLAY_SCALE) as u32;
-    let height = (specs::DISPLAY_HEIGHT * specs::DISPLAY_SCALE) as u32;
-    let window = video_ctx.window("Johnny Eight", width, height)
-        .position_centered()
-        .opengl()
-        .build()
-        .unwrap();
+    // Build the VM
+    let mut builder = VmBuilder::from_config(layout)
+        .ram_size(ram_size)
+        .quirks(quirks)
+        .timing(timing)
+        .rewind_frames(specs::REWIND_FRAMES)
+        .machine_code(machine_code)
+        .unknown_opcode_policy(unknown_opcode_policy)
+        .clock(Box::new(clock))
+        .rom(rom);
 
-    let mut renderer = window.renderer().build().unwrap();
-    let scale = specs::DISPLAY_SCALE as f32;
-    let _ = renderer.set_scale(scale, scale);
+    if let Some(seed) = seed {
+        builder = builder.seed(seed);
+    }
 
-    // Paint screen black
-    let _ = renderer.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
-    let _ = renderer.clear();
+    if let Some(frames) = screenshot_after {
+        match take_screenshot_after(builder, frames, &captures) {
+            Ok(path) => println!("Saved {}", path.display()),
+            Err(err) => {
+                println!("{}", err);
+                exit(1);
+            }
+        }
+    } else if let Some(seconds) = record_for {
+        match record(builder, seconds, &captures) {
+            Ok(path) => println!("Saved {}", path.display()),
+            Err(err) => {
+                println!("{}", err);
+                exit(1);
+            }
+        }
+    } else if in_terminal || !cfg!(feature = "sdl") {
+        if let Err(err) = tty::run_in_terminal(builder) {
+            println!("{}", err);
+            exit(1);
+        }
+    } else {
+        #[cfg(feature = "sdl")]
+        window::run_in_window(builder, &captures);
+    }
+}
+
+type Gif = GifRecorder<BufWriter<File>>;
 
-    // Display the black screen.
-    let _ = renderer.present();
+// Saves screenshots and recordings in the working directory, named after
+// the ROM.
+struct Captures {
+    name: String,
+    screenshot: Screenshot,
+    recording: Recording,
+}
 
-    // Build a Display with its data bus
-    let (bus, display) = Display::build();
+impl Captures {
+    fn new(rom_path: &str, screenshot: Screenshot, recording: Recording) -> Captures {
+        let name = Path::new(rom_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("capture"));
 
-    // Build the VM
-    let mut vm = VM::boot();
-    vm.load_sprites()
-        .load_rom(&mut rom)
-        .set_display_bus(bus)
-        .init_clock();
-
-    let mut events = ctx.event_pump().unwrap();
-
-    // loop until we receive a QuitEvent
-    'event: loop {
-        for event in events.poll_iter() {
-            match event {
-                Event::Quit { .. } |
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'event,
-
-                Event::KeyDown { keycode: Some(keycode), .. } => {
-                    match keycode {
-                        Keycode::Num1 => vm.set_key(Key::Num1),
-                        Keycode::Num2 => vm.set_key(Key::Num2),
-                        Keycode::Num3 => vm.set_key(Key::Num3),
-                        Keycode::Num4 => vm.set_key(Key::C),
-                        Keycode::Q => vm.set_key(Key::Num4),
-                        Keycode::W => vm.set_key(Key::Num5),
-                        Keycode::E => vm.set_key(Key::Num6),
-                        Keycode::R => vm.set_key(Key::D),
-                        Keycode::A => vm.set_key(Key::Num7),
-                        Keycode::S => vm.set_key(Key::Num8),
-                        Keycode::D => vm.set_key(Key::Num9),
-                        Keycode::F => vm.set_key(Key::E),
-                        Keycode::Z => vm.set_key(Key::A),
-                        Keycode::X => vm.set_key(Key::Num0),
-                        Keycode::C => vm.set_key(Key::B),
-                        Keycode::V => vm.set_key(Key::F),
-                        _ => {}
-                    };
-                }
+        Captures { name, screenshot, recording }
+    }
 
-                _ => {}
-            }
-        }
+    // Never overwrites an earlier capture.
+    fn next_path(&self, extension: &str) -> PathBuf {
+        (1..)
+            .map(|n| PathBuf::from(format!("{}-{}.{}", self.name, n, extension)))
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+
+    fn save_screenshot(&self, vm: &VM) -> Result<PathBuf, VmError> {
+        let path = self.next_path(self.screenshot.format.extension());
+        let mut file = File::create(&path)?;
+        vm.save_screenshot(&mut file, &self.screenshot)?;
+
+        Ok(path)
+    }
+
+    fn start_recording(&self) -> Result<(Gif, PathBuf), VmError> {
+        let path = self.next_path("gif");
+        let file = File::create(&path)?;
 
-        vm.cycle();
-        display.flush(&mut renderer);
+        Ok((GifRecorder::new(BufWriter::new(file), self.recording), path))
     }
 }
+
+// Runs without a display as fast as possible.
+fn take_screenshot_after(builder: VmBuilder,
+                         frames: usize,
+                         captures: &Captures)
+                         -> Result<PathBuf, VmError> {
+    let mut vm = builder.audio(Box::new(Silence)).build()?;
+    vm.run_for(frames)?;
+
+    captures.save_screenshot(&vm)
+}
+
+// Records the first seconds of the ROM, as fast as possible.
+fn record(builder: VmBuilder, seconds: usize, captures: &Captures) -> Result<PathBuf, VmError> {
+    let (gif, path) = captures.start_recording()?;
+    let gif = Arc::new(Mutex::new(gif));
+
+    let mut vm = builder.display(Box::new(gif.clone())).audio(Box::new(Silence)).build()?;
+    vm.run_for(seconds * specs::TIMER_FREQUENCY as usize)?;
+    gif.lock().unwrap().finish()?;
+
+    Ok(path)
+}
@@ -0,0 +1,352 @@
+// RCA CDP1802, the CPU of the COSMAC VIP.
+//
+// The original CHIP-8 interpreter runs machine code routines with 0NNN, so
+// VIP programs can drop into 1802 code for the things CHIP-8 can't do. This
+// core runs the whole 1802 instruction set against any `Memory`. There is no
+// I/O attached: INP reads 0, OUT is ignored and the EF lines are inactive.
+
+use memory::Memory;
+
+#[derive(Debug,Copy,Clone,PartialEq,Default)]
+pub struct Cdp1802 {
+    pub r: [u16; 16], // Scratchpad registers, any of them can be the PC
+    pub d: u8, // Accumulator
+    pub df: bool, // Carry
+    pub p: u8, // Selects the register used as the PC
+    pub x: u8, // Selects the register used as the data pointer
+    pub t: u8, // X and P saved by MARK and interrupts
+    pub ie: bool, // Interrupts enabled
+    pub q: bool, // Q output, drives the VIP speaker
+    pub idle: bool, // IDL ran and the CPU waits for an interrupt or DMA
+}
+
+impl Cdp1802 {
+    pub fn new() -> Cdp1802 {
+        Cdp1802 { ie: true, ..Cdp1802::default() }
+    }
+
+    /// Runs one instruction and returns the machine cycles it took.
+    pub fn step(&mut self, memory: &mut dyn Memory) -> u64 {
+        let opcode = self.fetch(memory);
+        let n = (opcode & 0x0F) as usize;
+
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = read(memory, self.r[n]),
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => self.short_branch(memory, n),
+            0x4 => {
+                self.d = read(memory, self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            0x5 => write(memory, self.r[n], self.d),
+            0x6 => self.input_output(memory, n),
+            0x7 => self.control(memory, n),
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16,
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | (self.d as u16) << 8,
+            0xC => {
+                self.long_branch(memory, n);
+                return 3;
+            }
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+            _ => self.alu(memory, n),
+        };
+
+        2
+    }
+
+    fn fetch(&mut self, memory: &mut dyn Memory) -> u8 {
+        let pc = self.p as usize;
+        let byte = read(memory, self.r[pc]);
+        self.r[pc] = self.r[pc].wrapping_add(1);
+
+        byte
+    }
+
+    // M(R(X))
+    fn data(&self, memory: &mut dyn Memory) -> u8 {
+        read(memory, self.r[self.x as usize])
+    }
+
+    fn increment_x(&mut self) {
+        let x = self.x as usize;
+        self.r[x] = self.r[x].wrapping_add(1);
+    }
+
+    // 3N: the branch replaces the low byte of the PC with the next byte.
+    fn short_branch(&mut self, memory: &mut dyn Memory, n: usize) {
+        let taken = self.condition(n & 0b111) != (n & 0b1000 != 0);
+        let pc = self.p as usize;
+
+        if taken {
+            let target = read(memory, self.r[pc]);
+            self.r[pc] = (self.r[pc] & 0xFF00) | target as u16;
+        } else {
+            self.r[pc] = self.r[pc].wrapping_add(1);
+        }
+    }
+
+    // 0-7 are BR, BQ, BZ, BDF and B1-B4; 8-F test the opposite.
+    fn condition(&self, n: usize) -> bool {
+        match n {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            _ => false, // EF1-EF4
+        }
+    }
+
+    // CN: long branches take the next two bytes as the new PC, long skips
+    // jump over them.
+    fn long_branch(&mut self, memory: &mut dyn Memory, n: usize) {
+        let condition = match n & 0b11 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            _ => self.df,
+        };
+        let skip = n & 0b100 != 0;
+        // Branches test the opposite condition from C8 on, skips before it.
+        let inverted = (n & 0b1000 != 0) != skip;
+        let taken = match n {
+            0x4 => false, // NOP
+            0xC => self.ie, // LSIE
+            _ => condition != inverted,
+        };
+
+        let pc = self.p as usize;
+        if taken && !skip {
+            let high = read(memory, self.r[pc]) as u16;
+            let low = read(memory, self.r[pc].wrapping_add(1)) as u16;
+            self.r[pc] = high << 8 | low;
+        } else if taken || !skip {
+            self.r[pc] = self.r[pc].wrapping_add(2);
+        }
+    }
+
+    // 60 is IRX, 61-67 OUT and 69-6F INP.
+    fn input_output(&mut self, memory: &mut dyn Memory, n: usize) {
+        match n {
+            // Nothing listens to OUT, the byte is dropped
+            0x0..=0x7 => self.increment_x(),
+            0x8 => (),
+            _ => {
+                self.d = 0;
+                write(memory, self.r[self.x as usize], self.d);
+            }
+        }
+    }
+
+    fn control(&mut self, memory: &mut dyn Memory, n: usize) {
+        match n {
+            // RET and DIS
+            0x0 | 0x1 => {
+                let xp = self.data(memory);
+                self.increment_x();
+                self.x = xp >> 4;
+                self.p = xp & 0x0F;
+                self.ie = n == 0x0;
+            }
+            // LDXA
+            0x2 => {
+                self.d = self.data(memory);
+                self.increment_x();
+            }
+            // STXD
+            0x3 => {
+                let x = self.x as usize;
+                write(memory, self.r[x], self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            // ADC, SDB, SMB
+            0x4 => self.add(self.data(memory), self.df),
+            0x5 => self.subtract(self.data(memory), self.d, self.df),
+            0x7 => self.subtract(self.d, self.data(memory), self.df),
+            // SHRC
+            0x6 => {
+                let carry = self.df as u8;
+                self.df = self.d & 1 != 0;
+                self.d = self.d >> 1 | carry << 7;
+            }
+            // SAV
+            0x8 => write(memory, self.r[self.x as usize], self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                write(memory, self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            // REQ and SEQ
+            0xA => self.q = false,
+            0xB => self.q = true,
+            // ADCI, SDBI, SMBI
+            0xC => {
+                let byte = self.fetch(memory);
+                self.add(byte, self.df);
+            }
+            0xD => {
+                let byte = self.fetch(memory);
+                self.subtract(byte, self.d, self.df);
+            }
+            0xF => {
+                let byte = self.fetch(memory);
+                self.subtract(self.d, byte, self.df);
+            }
+            // SHLC
+            _ => {
+                let carry = self.df as u8;
+                self.df = self.d & 0x80 != 0;
+                self.d = self.d << 1 | carry;
+            }
+        }
+    }
+
+    // FN: F0-F7 take the operand from M(R(X)), F8-FF from the next byte.
+    fn alu(&mut self, memory: &mut dyn Memory, n: usize) {
+        match n {
+            // SHR and SHL don't take an operand
+            0x6 => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            }
+            0xE => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            }
+            _ => {
+                let operand = if n < 0x8 {
+                    self.data(memory)
+                } else {
+                    self.fetch(memory)
+                };
+
+                match n & 0b111 {
+                    0x0 => self.d = operand,
+                    0x1 => self.d |= operand,
+                    0x2 => self.d &= operand,
+                    0x3 => self.d ^= operand,
+                    0x4 => self.add(operand, false),
+                    0x5 => self.subtract(operand, self.d, true),
+                    _ => self.subtract(self.d, operand, true),
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, operand: u8, carry: bool) {
+        let sum = self.d as u16 + operand as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // DF is set when there is no borrow. `no_borrow` is the DF going in.
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, no_borrow: bool) {
+        let difference = minuend as i16 - subtrahend as i16 - !no_borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+}
+
+// The VIP decodes fewer address lines than the 1802 has, so memory repeats
+// over the whole 64KB.
+fn read(memory: &mut dyn Memory, addr: u16) -> u8 {
+    let len = memory.len();
+    memory.read(addr as usize % len)
+}
+
+fn write(memory: &mut dyn Memory, addr: u16, value: u8) {
+    let len = memory.len();
+    memory.write(addr as usize % len, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, Vec<u8>) {
+        let mut memory = vec![0; 0x100];
+        memory[..program.len()].copy_from_slice(program);
+
+        let mut cpu = Cdp1802::new();
+        for _ in 0..steps {
+            cpu.step(&mut memory);
+        }
+
+        (cpu, memory)
+    }
+
+    #[test]
+    fn it_loads_and_stores_through_registers() {
+        // LDI 0x80; PLO R1; LDI 0x42; STR R1; LDN R1
+        let (cpu, memory) = run(&[0xF8, 0x80, 0xA1, 0xF8, 0x42, 0x51, 0x01], 5);
+
+        assert_eq!(0x0080, cpu.r[1]);
+        assert_eq!(0x42, memory[0x80]);
+        assert_eq!(0x42, cpu.d);
+        assert_eq!(7, cpu.r[0]);
+    }
+
+    #[test]
+    fn it_adds_and_subtracts_with_carry() {
+        // LDI 0xF0; ADI 0x20; SMI 0x20
+        let (cpu, _) = run(&[0xF8, 0xF0, 0xFC, 0x20, 0xFF, 0x20], 2);
+        assert_eq!(0x10, cpu.d);
+        assert!(cpu.df);
+
+        let (cpu, _) = run(&[0xF8, 0xF0, 0xFC, 0x20, 0xFF, 0x20], 3);
+        assert_eq!(0xF0, cpu.d);
+        assert!(!cpu.df); // Borrowed
+    }
+
+    #[test]
+    fn it_branches_within_the_page() {
+        // LDI 0; BZ 0x10; ... at 0x10: LDI 7
+        let mut program = vec![0xF8, 0x00, 0x32, 0x10];
+        program.resize(0x10, 0);
+        program.extend_from_slice(&[0xF8, 0x07]);
+
+        let (cpu, _) = run(&program, 3);
+        assert_eq!(0x07, cpu.d);
+    }
+
+    #[test]
+    fn it_takes_long_branches_and_skips() {
+        // LBR 0x0020; at 0x20: LSKP; LDI 1; LDI 2
+        let mut program = vec![0xC0, 0x00, 0x20];
+        program.resize(0x20, 0);
+        program.extend_from_slice(&[0xC8, 0xF8, 0x01, 0xF8, 0x02]);
+
+        let (cpu, _) = run(&program, 3);
+        assert_eq!(0x02, cpu.d);
+        assert_eq!(0x25, cpu.r[0]);
+    }
+
+    #[test]
+    fn it_switches_the_program_counter_with_sep() {
+        // LDI 0x10; PLO R3; SEP R3; at 0x10: LDI 9
+        let mut program = vec![0xF8, 0x10, 0xA3, 0xD3];
+        program.resize(0x10, 0);
+        program.extend_from_slice(&[0xF8, 0x09]);
+
+        let (cpu, _) = run(&program, 4);
+        assert_eq!(3, cpu.p);
+        assert_eq!(0x09, cpu.d);
+        assert_eq!(0x12, cpu.r[3]);
+    }
+
+    #[test]
+    fn it_pushes_and_pops_through_x() {
+        // LDI 0x80; PLO R2; SEX R2; LDI 5; STXD; IRX; LDX
+        let (cpu, memory) = run(&[0xF8, 0x80, 0xA2, 0xE2, 0xF8, 0x05, 0x73, 0x60, 0xF0], 7);
+
+        assert_eq!(0x05, memory[0x80]);
+        assert_eq!(0x80, cpu.r[2]);
+        assert_eq!(0x05, cpu.d);
+    }
+}
@@ -0,0 +1,234 @@
+// Clocks tell the VM how many 60 Hz frames it has to run.
+//
+// The VM asks its clock on every `cycle` and runs the pending frames, so the
+// same core can follow the wall clock, run as fast as possible or be driven
+// step by step by the host.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use specs;
+
+// The most frames a real-time clock hands out at once. If the host stalls for
+// longer (a debugger, a dragged window...) the missing time is dropped instead
+// of running the game in fast forward to catch up.
+const MAX_PENDING_FRAMES: u64 = 10;
+
+pub trait Clock {
+    /// Returns how many frames are due since the last call.
+    fn pending_frames(&mut self) -> u64;
+
+    /// How long until the next frame is due, for clocks that follow
+    /// the wall clock.
+    fn next_frame_in(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Follows the wall clock. Frames are counted from the moment the clock
+/// started instead of sleeping between them, so timing errors don't add up.
+#[derive(Debug,Copy,Clone)]
+pub struct RealtimeClock {
+    start: Instant,
+    frames: u64, // Frames handed out since the start
+    speed: f64,
+}
+
+impl RealtimeClock {
+    pub fn new() -> RealtimeClock {
+        RealtimeClock::with_speed(1.0)
+    }
+
+    fn with_speed(speed: f64) -> RealtimeClock {
+        RealtimeClock {
+            start: Instant::now(),
+            frames: 0,
+            speed,
+        }
+    }
+
+    // The frames due when `elapsed` went by since the start.
+    fn pending_frames_after(&mut self, elapsed: Duration) -> u64 {
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let due = (seconds * self.speed * specs::TIMER_FREQUENCY as f64) as u64;
+
+        let mut pending = due - self.frames;
+        if pending > MAX_PENDING_FRAMES {
+            pending = MAX_PENDING_FRAMES;
+        }
+        // Frames over the limit are dropped too.
+        self.frames = due;
+
+        pending
+    }
+
+    fn next_frame_after(&self, elapsed: Duration) -> Option<Duration> {
+        let next = (self.frames + 1) as f64 / (self.speed * specs::TIMER_FREQUENCY as f64);
+        // Too far away to tell, e.g. with a speed close to 0
+        let due = Duration::try_from_secs_f64(next).ok()?;
+
+        Some(due.checked_sub(elapsed).unwrap_or_default())
+    }
+}
+
+impl Default for RealtimeClock {
+    fn default() -> RealtimeClock {
+        RealtimeClock::new()
+    }
+}
+
+impl Clock for RealtimeClock {
+    fn pending_frames(&mut self) -> u64 {
+        self.pending_frames_after(self.start.elapsed())
+    }
+
+    fn next_frame_in(&self) -> Option<Duration> {
+        self.next_frame_after(self.start.elapsed())
+    }
+}
+
+/// A real-time clock running N times faster (or slower) than the wall clock.
+#[derive(Debug,Copy,Clone)]
+pub struct ScaledClock {
+    clock: RealtimeClock,
+}
+
+impl ScaledClock {
+    /// The speed must be a finite number over 0, 2 runs twice as fast.
+    pub fn new(speed: f64) -> Option<ScaledClock> {
+        if speed.is_finite() && speed > 0.0 {
+            Some(ScaledClock { clock: RealtimeClock::with_speed(speed) })
+        } else {
+            None
+        }
+    }
+}
+
+impl Clock for ScaledClock {
+    fn pending_frames(&mut self) -> u64 {
+        self.clock.pending_frames()
+    }
+
+    fn next_frame_in(&self) -> Option<Duration> {
+        self.clock.next_frame_in()
+    }
+}
+
+/// Runs unthrottled: every `cycle` runs the given number of frames.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct FastForwardClock {
+    frames: u64,
+}
+
+impl FastForwardClock {
+    pub fn new(frames: u64) -> FastForwardClock {
+        FastForwardClock { frames }
+    }
+}
+
+impl Clock for FastForwardClock {
+    fn pending_frames(&mut self) -> u64 {
+        self.frames
+    }
+}
+
+/// Driven by the host. Clones share the same frames, so one clone can be
+/// given to the VM and another kept to advance it.
+#[derive(Debug,Clone,Default)]
+pub struct ManualClock {
+    pending: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    pub fn advance(&self, frames: u64) {
+        self.pending.fetch_add(frames, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn pending_frames(&mut self) -> u64 {
+        self.pending.swap(0, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn the_realtime_clock_follows_the_wall_clock() {
+        let mut clock = RealtimeClock::new();
+
+        assert_eq!(0, clock.pending_frames_after(ms(10)));
+        assert_eq!(3, clock.pending_frames_after(ms(50)));
+        assert_eq!(0, clock.pending_frames_after(ms(60)));
+        assert_eq!(1, clock.pending_frames_after(ms(67)));
+    }
+
+    #[test]
+    fn the_realtime_clock_drops_frames_after_a_stall() {
+        let mut clock = RealtimeClock::new();
+
+        assert_eq!(MAX_PENDING_FRAMES, clock.pending_frames_after(ms(250)));
+        // It goes on from there, the dropped frames don't come back
+        assert_eq!(1, clock.pending_frames_after(ms(267)));
+    }
+
+    #[test]
+    fn the_realtime_clock_knows_when_the_next_frame_is_due() {
+        let mut clock = RealtimeClock::new();
+        clock.pending_frames_after(ms(20));
+
+        // The second frame is due at 33.3ms
+        let wait = clock.next_frame_after(ms(20)).unwrap();
+        assert!(wait > ms(13) && wait < ms(14), "{:?}", wait);
+        assert_eq!(Some(Duration::default()), clock.next_frame_after(ms(40)));
+        assert_eq!(None, FastForwardClock::new(1).next_frame_in());
+    }
+
+    #[test]
+    fn the_scaled_clock_runs_faster() {
+        let mut clock = ScaledClock::new(4.0).unwrap();
+
+        assert_eq!(6, clock.clock.pending_frames_after(ms(25)));
+    }
+
+    #[test]
+    fn the_scaled_clock_needs_a_positive_speed() {
+        for &speed in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(ScaledClock::new(speed).is_none(), "{}", speed);
+        }
+
+        let clock = ScaledClock::new(1e-300).unwrap();
+        assert_eq!(None, clock.next_frame_in());
+    }
+
+    #[test]
+    fn the_fast_forward_clock_always_has_frames() {
+        let mut clock = FastForwardClock::new(3);
+
+        assert_eq!(3, clock.pending_frames());
+        assert_eq!(3, clock.pending_frames());
+    }
+
+    #[test]
+    fn the_manual_clock_is_advanced_by_the_host() {
+        let handle = ManualClock::new();
+        let mut clock = handle.clone();
+        assert_eq!(0, clock.pending_frames());
+
+        handle.advance(2);
+        handle.advance(1);
+        assert_eq!(3, clock.pending_frames());
+        assert_eq!(0, clock.pending_frames());
+    }
+}
@@ -1,64 +1,297 @@
-use sdl2::render::Renderer;
-use sdl2::rect::Point;
-use sdl2::pixels::Color;
-use std::sync::mpsc::{channel, Sender, Receiver};
-
-#[derive(Debug)]
-pub struct Pixel {
-    x: i32,
-    y: i32,
-    value: u8,
-}
-
-impl Pixel {
-    pub fn new(x: i32, y: i32, value: u8) -> Pixel {
-        Pixel {
-            x: x,
-            y: y,
-            value: value,
+// Display backends
+//
+// The VM publishes the screen once per 60 Hz frame, at vblank, and only if
+// something changed. Each `Frame` carries the whole screen and the area that
+// changed since the previous one, so a renderer can redraw just that part.
+// The library doesn't draw anything itself: the SDL window lives in the
+// binary and other frontends bring their own `DisplaySink`.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+
+use specs;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+/// The color of a pixel value. Each bit of the value is a bitplane.
+pub fn color(value: u8) -> Rgb {
+    match value {
+        1 => Rgb::new(255, 255, 255),
+        2 => Rgb::new(170, 170, 170),
+        3 => Rgb::new(85, 85, 85),
+        _ => Rgb::new(0, 0, 0),
+    }
+}
+
+/// The colors of the four pixel values, for images of the screen.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Palette(pub [Rgb; 4]);
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette([color(0), color(1), color(2), color(3)])
+    }
+}
+
+impl Palette {
+    pub fn color(&self, value: u8) -> Rgb {
+        self.0[(value & 0b11) as usize]
+    }
+
+    /// Reads hex colors separated by commas, e.g. `000000,ffffff`. With only
+    /// two colors the other planes keep their default ones.
+    pub fn parse(colors: &str) -> Option<Palette> {
+        let colors = colors.split(',').map(parse_hex).collect::<Option<Vec<_>>>()?;
+        if colors.len() != 2 && colors.len() != 4 {
+            return None;
         }
+
+        let mut palette = Palette::default();
+        palette.0[..colors.len()].copy_from_slice(&colors);
+
+        Some(palette)
+    }
+}
+
+fn parse_hex(color: &str) -> Option<Rgb> {
+    let color = color.trim().trim_start_matches('#');
+    if color.len() != 6 || !color.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
     }
 
-    pub fn as_point(&self) -> Point {
-        Point::new(self.x, self.y)
+    let value = u32::from_str_radix(color, 16).ok()?;
+    Some(Rgb::new((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
+
+/// An area of the screen, in pixels.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
     }
 
-    pub fn as_color(&self) -> Color {
-        if self.value == 1 {
-            Color::RGB(255, 255, 255)
-        } else {
-            Color::RGB(0, 0, 0)
+    /// The smallest area that covers both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = cmp::min(self.x, other.x);
+        let y = cmp::min(self.y, other.y);
+        let right = cmp::max(self.x + self.width, other.x + other.width);
+        let bottom = cmp::max(self.y + self.height, other.y + other.height);
+
+        Rect::new(x, y, right - x, bottom - y)
+    }
+}
+
+/// The whole screen, a pixel value per position row by row.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    pub dirty: Rect, // Changed since the previous frame
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Frame {
+        Frame {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            dirty: Rect::new(0, 0, width, height),
         }
     }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
 }
 
-#[derive(Debug)]
-pub struct Display {
-    port: Receiver<Vec<Pixel>>,
+impl Default for Frame {
+    fn default() -> Frame {
+        Frame::new(specs::DISPLAY_WIDTH, specs::DISPLAY_HEIGHT)
+    }
 }
 
-impl Display {
-    pub fn build() -> (Sender<Vec<Pixel>>, Display) {
-        let (transmitter, port): (Sender<Vec<Pixel>>, Receiver<Vec<Pixel>>) = channel();
+/// The display went away, e.g. its window was closed.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Disconnected;
+
+pub trait DisplaySink {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected>;
 
-        let display = Display { port: port };
+    /// A vblank went by without changes, the last frame is still on the
+    /// screen. Only sinks that keep time need it, e.g. recorders.
+    fn repeat(&mut self) {}
+}
 
-        (transmitter, display)
+// Lets the host keep a handle on a sink the VM owns, e.g. to stop a
+// recording.
+impl<S: DisplaySink> DisplaySink for Arc<Mutex<S>> {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected> {
+        self.lock().map_err(|_| Disconnected)?.present(frame)
     }
 
-    pub fn flush(&self, renderer: &mut Renderer) {
-        match self.port.try_recv() {
-            Ok(pixels) => {
-                for pixel in pixels.iter() {
-                    trace!("Rendering pixel {:?}", pixel);
+    fn repeat(&mut self) {
+        if let Ok(mut sink) = self.lock() {
+            sink.repeat()
+        }
+    }
+}
 
-                    let _ = renderer.set_draw_color(pixel.as_color());
-                    let _ = renderer.draw_point(pixel.as_point());
-                }
+/// Drops every frame, for headless runs.
+#[derive(Debug,Copy,Clone,Default)]
+pub struct NullSink;
 
-                let _ = renderer.present();
-            }
-            _ => {} // TODO: Handle disconnections
+impl DisplaySink for NullSink {
+    fn present(&mut self, _frame: &Frame) -> Result<(), Disconnected> {
+        Ok(())
+    }
+}
+
+/// Keeps the last frame in memory. Clones share the same frame, so one
+/// clone can be given to the VM and another kept to look at the screen.
+#[derive(Debug,Clone,Default)]
+pub struct FramebufferSink {
+    frame: Arc<Mutex<Frame>>,
+}
+
+impl FramebufferSink {
+    pub fn new() -> FramebufferSink {
+        FramebufferSink::default()
+    }
+
+    /// A copy of the last frame.
+    pub fn frame(&self) -> Frame {
+        self.frame.lock().unwrap().clone()
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.frame.lock().unwrap().pixel(x, y)
+    }
+}
+
+impl DisplaySink for FramebufferSink {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected> {
+        self.frame.lock().map_err(|_| Disconnected)?.clone_from(frame);
+
+        Ok(())
+    }
+}
+
+/// Hands the frames to a renderer on another thread. Only the latest
+/// frame is kept: if the renderer falls behind, the frames it missed are
+/// dropped and their changed areas added to the next one.
+pub fn latest_frame() -> (FrameSender, FrameReceiver) {
+    let slot = Arc::new(Mutex::new(None));
+
+    (FrameSender { slot: slot.clone() }, FrameReceiver { slot })
+}
+
+pub struct FrameSender {
+    slot: Arc<Mutex<Option<Frame>>>,
+}
+
+impl DisplaySink for FrameSender {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected> {
+        // The receiver is gone
+        if Arc::strong_count(&self.slot) == 1 {
+            return Err(Disconnected);
         }
+
+        let mut slot = self.slot.lock().map_err(|_| Disconnected)?;
+        let mut latest = frame.clone();
+        if let Some(ref missed) = *slot {
+            latest.dirty = if (missed.width, missed.height) == (frame.width, frame.height) {
+                missed.dirty.union(&frame.dirty)
+            } else {
+                Rect::new(0, 0, frame.width, frame.height)
+            };
+        }
+        *slot = Some(latest);
+
+        Ok(())
+    }
+}
+
+pub struct FrameReceiver {
+    slot: Arc<Mutex<Option<Frame>>>,
+}
+
+impl FrameReceiver {
+    /// Returns the latest frame, if there is a new one since the last call.
+    pub fn take(&self) -> Option<Frame> {
+        match self.slot.lock() {
+            Ok(mut slot) => slot.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_framebuffer_sink_keeps_the_last_frame() {
+        let screen = FramebufferSink::new();
+        let mut sink = screen.clone();
+
+        let mut frame = Frame::new(128, 64);
+        frame.pixels[2 * 128 + 3] = 1;
+        sink.present(&frame).unwrap();
+
+        assert_eq!(frame, screen.frame());
+        assert_eq!(1, screen.pixel(3, 2));
+    }
+
+    #[test]
+    fn the_latest_frame_merges_the_frames_it_missed() {
+        let (mut sender, receiver) = latest_frame();
+
+        let mut frame = Frame { dirty: Rect::new(1, 1, 2, 2), ..Frame::default() };
+        sender.present(&frame).unwrap();
+        frame.dirty = Rect::new(10, 4, 1, 1);
+        sender.present(&frame).unwrap();
+
+        assert_eq!(Rect::new(1, 1, 10, 4), receiver.take().unwrap().dirty);
+        assert_eq!(None, receiver.take());
+
+        drop(receiver);
+        assert_eq!(Err(Disconnected), sender.present(&frame));
+    }
+
+    #[test]
+    fn pixel_values_map_their_planes_to_colors() {
+        assert_eq!(Rgb::new(0, 0, 0), color(0));
+        assert_eq!(Rgb::new(255, 255, 255), color(1));
+    }
+
+    #[test]
+    fn palettes_are_read_from_hex_colors() {
+        let palette = Palette::parse("#102030,ffa000").unwrap();
+
+        assert_eq!(Rgb::new(0x10, 0x20, 0x30), palette.color(0));
+        assert_eq!(Rgb::new(0xff, 0xa0, 0x00), palette.color(1));
+        assert_eq!(color(3), palette.color(3));
+
+        assert_eq!(None, Palette::parse("000000"));
+        assert_eq!(None, Palette::parse("000000,fffff"));
     }
 }
@@ -0,0 +1,429 @@
+// Animated GIF recorder.
+//
+// The recorder is a display sink: every frame presented or repeated by the
+// VM is a 60th of a second. With a `frame_step` above 1 only one frame of
+// each step is kept. Equal frames are merged into one that lasts longer and
+// the others only store the area that changed since the last one.
+//
+// GIF delays are in hundredths of a second, so they are rounded from the
+// frame count since the start and the clip doesn't drift. Many viewers
+// play delays under 2/100 slower, which is why the default is 30 fps.
+//
+// The size of the GIF is set by the first frame. Frames of another
+// resolution, e.g. after a SUPER-CHIP game switches modes, are resized to it.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use display::{Disconnected, DisplaySink, Frame, Palette};
+
+/// How a recording is saved.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Recording {
+    pub scale: usize,
+    pub palette: Palette,
+    pub frame_step: usize, // 1 keeps every frame, 60 fps, 2 every other one...
+}
+
+impl Default for Recording {
+    fn default() -> Recording {
+        Recording {
+            scale: 4,
+            palette: Palette::default(),
+            frame_step: 2,
+        }
+    }
+}
+
+pub struct GifRecorder<W: Write> {
+    writer: W,
+    settings: Recording,
+    size: (usize, usize), // Of the screen, before scaling
+    screen: Vec<u8>, // Latest screen
+    frames: usize, // Seen since the start
+    pending: Option<(Vec<u8>, usize)>, // Screen to write and the frame it was taken
+    written: Option<Vec<u8>>, // Last screen written
+    error: Option<io::Error>, // The first write that failed
+    finished: bool,
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(writer: W, settings: Recording) -> GifRecorder<W> {
+        GifRecorder {
+            writer,
+            settings,
+            size: (0, 0),
+            screen: vec![],
+            frames: 0,
+            pending: None,
+            written: None,
+            error: None,
+            finished: false,
+        }
+    }
+
+    /// Seconds recorded so far.
+    pub fn duration(&self) -> f64 {
+        self.frames as f64 / 60.0
+    }
+
+    /// Writes the last frame and the end of the GIF. Any error while
+    /// recording is returned here. Frames after it are ignored.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        if let Some((screen, start)) = self.pending.take() {
+            let result = self.write_frame(&screen, start, self.frames)
+                .and_then(|_| self.writer.write_all(&[0x3B]))
+                .and_then(|_| self.writer.flush());
+            self.keep_error(result);
+        } else if self.error.is_none() {
+            return Err(io::Error::other("no frames were recorded"));
+        }
+
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn tick(&mut self, frame: Option<&Frame>) {
+        if self.finished {
+            return;
+        }
+
+        if let Some(frame) = frame {
+            if self.size == (0, 0) {
+                self.size = (frame.width, frame.height);
+                let result = self.write_header();
+                self.keep_error(result);
+            }
+            self.screen = resize(frame, self.size);
+        } else if self.size == (0, 0) {
+            // Nothing to show yet
+            return;
+        }
+
+        let now = self.frames;
+        self.frames += 1;
+        if !now.is_multiple_of(cmp::max(self.settings.frame_step, 1)) {
+            return;
+        }
+
+        let changed = match self.pending {
+            Some((ref screen, _)) => *screen != self.screen,
+            None => true,
+        };
+        if changed {
+            if let Some((screen, start)) = self.pending.take() {
+                let result = self.write_frame(&screen, start, now);
+                self.keep_error(result);
+            }
+            self.pending = Some((self.screen.clone(), now));
+        }
+    }
+
+    fn keep_error(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let (width, height) = self.scaled(self.size.0, self.size.1)?;
+
+        let mut header = b"GIF89a".to_vec();
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        // A global table of 4 colors, background 0, square pixels
+        header.extend_from_slice(&[0x91, 0, 0]);
+        for color in self.settings.palette.0.iter() {
+            header.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+        // Loop forever
+        header.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+        header.extend_from_slice(b"NETSCAPE2.0");
+        header.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+
+        self.writer.write_all(&header)
+    }
+
+    // Shown from the frame `start` to `end`.
+    fn write_frame(&mut self, screen: &[u8], start: usize, end: usize) -> io::Result<()> {
+        let centiseconds = |frame: usize| (frame * 100 + 30) / 60;
+        let delay = cmp::min(centiseconds(end) - centiseconds(start), 0xFFFF) as u16;
+
+        let (x, y, width, height) = match self.written {
+            Some(ref written) => changed_area(written, screen, self.size.0),
+            None => (0, 0, self.size.0, self.size.1),
+        };
+
+        let scale = self.settings.scale;
+        let mut pixels = Vec::with_capacity(width * height * scale * scale);
+        for row in y * scale..(y + height) * scale {
+            for column in x * scale..(x + width) * scale {
+                pixels.push(screen[row / scale * self.size.0 + column / scale] & 0b11);
+            }
+        }
+
+        let mut block = vec![];
+        // Graphic control: the frame stays under the next one
+        block.extend_from_slice(&[0x21, 0xF9, 0x04, 0x04]);
+        block.extend_from_slice(&delay.to_le_bytes());
+        block.extend_from_slice(&[0x00, 0x00]);
+        // Image descriptor, without a local color table
+        block.push(0x2C);
+        for value in &[x, y, width, height] {
+            block.extend_from_slice(&self.scaled(*value, 0)?.0.to_le_bytes());
+        }
+        block.push(0x00);
+
+        block.push(MIN_CODE_SIZE);
+        for chunk in lzw(&pixels).chunks(255) {
+            block.push(chunk.len() as u8);
+            block.extend_from_slice(chunk);
+        }
+        block.push(0x00);
+
+        self.writer.write_all(&block)?;
+        self.written = Some(screen.to_vec());
+
+        Ok(())
+    }
+
+    fn scaled(&self, width: usize, height: usize) -> io::Result<(u16, u16)> {
+        let scale = self.settings.scale;
+        if scale == 0 || width * scale > 0xFFFF || height * scale > 0xFFFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the scale of the recording is out of range"));
+        }
+
+        Ok(((width * scale) as u16, (height * scale) as u16))
+    }
+}
+
+impl<W: Write> DisplaySink for GifRecorder<W> {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected> {
+        self.tick(Some(frame));
+        Ok(())
+    }
+
+    fn repeat(&mut self) {
+        self.tick(None);
+    }
+}
+
+fn resize(frame: &Frame, size: (usize, usize)) -> Vec<u8> {
+    let (width, height) = size;
+    if (frame.width, frame.height) == size {
+        return frame.pixels.clone();
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(frame.pixel(x * frame.width / width, y * frame.height / height));
+        }
+    }
+
+    pixels
+}
+
+// The smallest area with every pixel that differs, as (x, y, width, height).
+// A frame needs at least one pixel, so equal screens give the first one.
+fn changed_area(before: &[u8], after: &[u8], width: usize) -> (usize, usize, usize, usize) {
+    let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+    for (i, _) in before.iter().zip(after).enumerate().filter(|&(_, (a, b))| a != b) {
+        let (x, y) = (i % width, i / width);
+        left = cmp::min(left, x);
+        top = cmp::min(top, y);
+        right = cmp::max(right, x + 1);
+        bottom = cmp::max(bottom, y + 1);
+    }
+
+    if left == usize::MAX {
+        return (0, 0, 1, 1);
+    }
+
+    (left, top, right - left, bottom - top)
+}
+
+// Codes start with 3 bits: the 4 colors, clear and end.
+const MIN_CODE_SIZE: u8 = 2;
+const MAX_CODES: u16 = 4096;
+
+fn lzw(pixels: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << MIN_CODE_SIZE;
+    let end = clear + 1;
+
+    let mut bits = BitWriter::default();
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = MIN_CODE_SIZE + 1;
+    bits.write(clear, size);
+
+    let mut prefix = None;
+    for &pixel in pixels {
+        let current = match prefix {
+            None => pixel as u16,
+            Some(prefix) => {
+                if let Some(&code) = codes.get(&(prefix, pixel)) {
+                    code
+                } else {
+                    bits.write(prefix, size);
+                    if next < MAX_CODES {
+                        codes.insert((prefix, pixel), next);
+                        next += 1;
+                        // The decoder adds its codes one step behind
+                        if next > 1 << size && size < 12 {
+                            size += 1;
+                        }
+                    } else {
+                        // The table is full, start over
+                        bits.write(clear, size);
+                        codes.clear();
+                        next = end + 1;
+                        size = MIN_CODE_SIZE + 1;
+                    }
+                    pixel as u16
+                }
+            }
+        };
+        prefix = Some(current);
+    }
+
+    if let Some(prefix) = prefix {
+        bits.write(prefix, size);
+        // The decoder adds a code for this one too
+        if next < MAX_CODES && next + 1 > 1 << size && size < 12 {
+            size += 1;
+        }
+    }
+    bits.write(end, size);
+
+    bits.finish()
+}
+
+// GIF packs the codes from their least significant bit.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.current |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.current as u8);
+            self.current >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.current as u8);
+        }
+
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(frames: &[Option<Frame>], settings: Recording) -> Vec<u8> {
+        let mut recorder = GifRecorder::new(vec![], settings);
+        for frame in frames {
+            match *frame {
+                Some(ref frame) => recorder.present(frame).unwrap(),
+                None => recorder.repeat(),
+            }
+        }
+        recorder.finish().unwrap();
+
+        recorder.writer
+    }
+
+    // Delays of the graphic control blocks, in order.
+    fn delays(gif: &[u8]) -> Vec<u16> {
+        gif.windows(4)
+            .enumerate()
+            .filter(|&(_, window)| window == [0x21, 0xF9, 0x04, 0x04])
+            .map(|(i, _)| u16::from_le_bytes([gif[i + 4], gif[i + 5]]))
+            .collect()
+    }
+
+    #[test]
+    fn it_encodes_codes_like_the_gif_spec() {
+        // Clear, 0, the code 6 for 0 0, 0 and end, which takes 4 bits
+        assert_eq!(vec![0x84, 0x51], lzw(&[0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn equal_frames_are_merged_into_a_longer_one() {
+        let blank = Frame::new(4, 2);
+        let mut drawn = blank.clone();
+        drawn.pixels[5] = 1;
+        let frames = [Some(blank.clone()), None, None, Some(drawn), None, Some(blank)];
+
+        let gif = record(&frames, Recording { frame_step: 1, ..Recording::default() });
+
+        assert_eq!(b"GIF89a", &gif[..6]);
+        assert_eq!(&[16, 0, 8, 0], &gif[6..10]); // Scaled 4 times
+        // 3, 2 and 1 frames of 60 fps
+        assert_eq!(vec![5, 3, 2], delays(&gif));
+        assert_eq!(Some(&0x3B), gif.last());
+    }
+
+    #[test]
+    fn decimated_recordings_keep_one_frame_per_step() {
+        let blank = Frame::new(4, 2);
+        let mut other = blank.clone();
+        other.pixels[0] = 1;
+        let mut drawn = blank.clone();
+        drawn.pixels[1] = 1;
+        // Only the frames 0, 2 and 4 are kept, the other one is never seen
+        let frames = [Some(blank.clone()), Some(other), Some(drawn.clone()), Some(drawn),
+                      Some(blank), None];
+
+        let gif = record(&frames, Recording { frame_step: 2, ..Recording::default() });
+
+        assert_eq!(vec![3, 4, 3], delays(&gif));
+    }
+
+    #[test]
+    fn only_the_area_that_changed_is_written() {
+        let blank = Frame::new(8, 4);
+        let mut drawn = blank.clone();
+        drawn.pixels[2 * 8 + 3] = 1;
+        drawn.pixels[3 * 8 + 5] = 1;
+
+        let gif = record(&[Some(blank), Some(drawn)],
+                         Recording { scale: 1, frame_step: 1, ..Recording::default() });
+
+        // The second image descriptor covers (3, 2) to (5, 3)
+        let descriptors = gif.windows(2)
+            .enumerate()
+            .filter(|&(_, window)| window == [0x00, 0x2C])
+            .map(|(i, _)| &gif[i + 2..i + 10])
+            .collect::<Vec<_>>();
+        assert_eq!(&[3, 0, 2, 0, 3, 0, 2, 0], descriptors[1]);
+    }
+
+    #[test]
+    fn recordings_without_frames_fail() {
+        let mut recorder = GifRecorder::new(vec![], Recording::default());
+        recorder.repeat();
+
+        assert!(recorder.finish().is_err());
+    }
+}
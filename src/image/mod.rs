@@ -0,0 +1,121 @@
+// Images of the screen, for bug reports and galleries.
+//
+// Every pixel of the screen becomes a square of `scale` by `scale` pixels,
+// painted with the color the palette gives to its value.
+
+mod gif;
+mod png;
+
+use std::io::{self, Write};
+
+use display::{Frame, Palette};
+use specs;
+
+pub use image::gif::{GifRecorder, Recording};
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Ppm, // Plain PPM (P3), text that any tool can read or diff
+}
+
+impl ImageFormat {
+    pub fn parse(name: &str) -> Option<ImageFormat> {
+        match name.to_lowercase().as_ref() {
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// How a screenshot is saved.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Screenshot {
+    pub format: ImageFormat,
+    pub scale: usize,
+    pub palette: Palette,
+}
+
+impl Default for Screenshot {
+    fn default() -> Screenshot {
+        Screenshot {
+            format: ImageFormat::Png,
+            scale: specs::DISPLAY_SCALE,
+            palette: Palette::default(),
+        }
+    }
+}
+
+impl Screenshot {
+    pub fn write(&self, frame: &Frame, writer: &mut dyn Write) -> io::Result<()> {
+        if self.scale == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the scale of a screenshot can't be 0"));
+        }
+
+        match self.format {
+            ImageFormat::Png => png::write(frame, self.scale, &self.palette, writer),
+            ImageFormat::Ppm => write_ppm(frame, self.scale, &self.palette, writer),
+        }
+    }
+}
+
+fn write_ppm(frame: &Frame,
+             scale: usize,
+             palette: &Palette,
+             writer: &mut dyn Write)
+             -> io::Result<()> {
+    let (width, height) = (frame.width * scale, frame.height * scale);
+
+    // A pixel per line keeps the lines under the 70 characters of the format
+    let mut image = format!("P3\n{} {}\n255\n", width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let color = palette.color(frame.pixel(x / scale, y / scale));
+            image.push_str(&format!("{} {} {}\n", color.r, color.g, color.b));
+        }
+    }
+
+    writer.write_all(image.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use display::Rgb;
+
+    #[test]
+    fn it_writes_scaled_ppm_images() {
+        let mut frame = Frame::new(2, 1);
+        frame.pixels[1] = 1;
+        let screenshot = Screenshot {
+            format: ImageFormat::Ppm,
+            scale: 2,
+            palette: Palette([Rgb::new(1, 2, 3), Rgb::new(4, 5, 6), Rgb::new(0, 0, 0), Rgb::new(0, 0, 0)]),
+        };
+
+        let mut image = vec![];
+        screenshot.write(&frame, &mut image).unwrap();
+
+        let mut expected = String::from("P3\n4 2\n255\n");
+        for _ in 0..2 {
+            expected.push_str("1 2 3\n1 2 3\n4 5 6\n4 5 6\n");
+        }
+        assert_eq!(expected, String::from_utf8(image).unwrap());
+    }
+
+    #[test]
+    fn the_scale_cant_be_zero() {
+        let screenshot = Screenshot { scale: 0, ..Screenshot::default() };
+
+        assert!(screenshot.write(&Frame::default(), &mut vec![]).is_err());
+    }
+}
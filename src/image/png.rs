@@ -0,0 +1,235 @@
+// PNG encoder for screenshots.
+//
+// The four pixel values fit in an indexed image of 2 bits per pixel. The
+// data is compressed with the fixed Huffman codes of deflate, matching runs
+// of the same byte and rows equal to the one above, which is most of a
+// scaled up screen.
+
+use std::io::{self, Write};
+
+use display::{Frame, Palette};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+pub fn write(frame: &Frame,
+             scale: usize,
+             palette: &Palette,
+             writer: &mut dyn Write)
+             -> io::Result<()> {
+    let (width, height) = (frame.width * scale, frame.height * scale);
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 2 bits per pixel, indexed colors, no interlacing
+    header.extend_from_slice(&[2, 3, 0, 0, 0]);
+
+    let mut colors = vec![];
+    for color in palette.0.iter() {
+        colors.extend_from_slice(&[color.r, color.g, color.b]);
+    }
+
+    // Each row starts with its filter, none, and packs 4 pixels per byte
+    let stride = width.div_ceil(4) + 1;
+    let mut rows = Vec::with_capacity(stride * height);
+    for y in 0..height {
+        rows.push(0);
+        let mut byte = 0;
+        for x in 0..width {
+            byte |= (frame.pixel(x / scale, y / scale) & 0b11) << (6 - 2 * (x % 4));
+            if x % 4 == 3 {
+                rows.push(byte);
+                byte = 0;
+            }
+        }
+        if width % 4 != 0 {
+            rows.push(byte);
+        }
+    }
+
+    writer.write_all(&SIGNATURE)?;
+    write_chunk(writer, b"IHDR", &header)?;
+    write_chunk(writer, b"PLTE", &colors)?;
+    write_chunk(writer, b"IDAT", &zlib(&rows, stride))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk(writer: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let crc = crc32(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+
+    writer.write_all(&chunk)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    b << 16 | a
+}
+
+fn zlib(data: &[u8], stride: usize) -> Vec<u8> {
+    // Deflate with a 32KB window and no dictionary
+    let mut stream = vec![0x78, 0x01];
+    stream.extend(deflate(data, stride));
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+
+    stream
+}
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_DISTANCE: usize = 32768;
+
+const LENGTH_BASE: [usize; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35,
+                                  43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4,
+                                4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [usize; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                                    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                                    8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9,
+                                  9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// A single block with the fixed codes. Only two distances are tried: the
+// previous byte and the previous row.
+fn deflate(data: &[u8], stride: usize) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.write(1, 1); // Last block
+    bits.write(1, 2); // Fixed Huffman codes
+
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = [1, stride]
+            .iter()
+            .filter(|&&distance| distance <= i && distance <= MAX_DISTANCE)
+            .map(|&distance| (match_length(data, i, distance), distance))
+            .max()
+            .unwrap_or((0, 0));
+
+        if length >= MIN_MATCH {
+            write_match(&mut bits, length, distance);
+            i += length;
+        } else {
+            write_literal(&mut bits, data[i] as u16);
+            i += 1;
+        }
+    }
+    write_literal(&mut bits, 256); // End of block
+
+    bits.finish()
+}
+
+fn match_length(data: &[u8], at: usize, distance: usize) -> usize {
+    data[at..]
+        .iter()
+        .zip(&data[at - distance..])
+        .take(MAX_MATCH)
+        .take_while(|&(a, b)| a == b)
+        .count()
+}
+
+fn write_literal(bits: &mut BitWriter, value: u16) {
+    match value {
+        0..=143 => bits.write_code(0b0011_0000 + value as u32, 8),
+        144..=255 => bits.write_code(0b1_1001_0000 + (value - 144) as u32, 9),
+        256..=279 => bits.write_code((value - 256) as u32, 7),
+        _ => bits.write_code(0b1100_0000 + (value - 280) as u32, 8),
+    }
+}
+
+fn write_match(bits: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| base <= length).unwrap();
+    write_literal(bits, 257 + code as u16);
+    bits.write((length - LENGTH_BASE[code]) as u32, LENGTH_EXTRA[code]);
+
+    let code = DISTANCE_BASE.iter().rposition(|&base| base <= distance).unwrap();
+    bits.write_code(code as u32, 5);
+    bits.write((distance - DISTANCE_BASE[code]) as u32, DISTANCE_EXTRA[code]);
+}
+
+// Deflate packs values from the least significant bit, but Huffman codes
+// from their most significant one.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u8) {
+        for bit in 0..count {
+            self.current |= (value >> bit & 1) << self.count;
+            self.count += 1;
+            if self.count == 8 {
+                self.bytes.push(self.current as u8);
+                self.current = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    fn write_code(&mut self, code: u32, count: u8) {
+        let reversed = code.reverse_bits() >> (32 - count as u32);
+        self.write(reversed, count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.current as u8);
+        }
+
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checksums_like_zlib() {
+        assert_eq!(0xAE42_6082, crc32(b"IEND"));
+        assert_eq!(0x11E6_0398, adler32(b"Wikipedia"));
+    }
+
+    #[test]
+    fn it_compresses_with_fixed_codes() {
+        assert_eq!(vec![0x78, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01], zlib(&[], 1));
+
+        // "a" and a run of 9 more, from the byte before
+        assert_eq!(vec![0x4B, 0x84, 0x03, 0x00], deflate(b"aaaaaaaaaa", 1));
+    }
+
+    #[test]
+    fn it_writes_an_indexed_image() {
+        let mut image = vec![];
+        write(&Frame::default(), 2, &Palette::default(), &mut image).unwrap();
+
+        assert_eq!(&SIGNATURE, &image[..8]);
+        // IHDR: 128x64, 2 bits per pixel with a palette
+        assert_eq!(&[0, 0, 0, 128, 0, 0, 0, 64, 2, 3, 0, 0, 0], &image[16..29]);
+        assert_eq!(b"PLTE", &image[37..41]);
+        assert!(image.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+    }
+}
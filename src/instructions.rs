@@ -19,7 +19,7 @@ pub struct Opcode {
 impl Opcode {
     pub fn new(bytes: u16) -> Opcode {
         Opcode {
-            bytes: bytes,
+            bytes,
             address: ADDRESS_MASK & bytes,
             x: ((VX_MASK & bytes) >> 8) as u8,
             y: ((VY_MASK & bytes) >> 4) as u8,
@@ -30,13 +30,30 @@ impl Opcode {
     }
 }
 
-#[derive(PartialEq,Debug)]
+#[derive(PartialEq,Debug,Copy,Clone)]
 pub enum Instruction {
     // CLS; Clear the display
     Clear,
     // RET; Return from a subroutine
     Return,
 
+    // SCD nibble; Scroll the display nibble lines down
+    ScrollDown(Opcode),
+    // SCU nibble; Scroll the display nibble lines up
+    ScrollUp(Opcode),
+    // SCR; Scroll the display 4 pixels right
+    ScrollRight,
+    // SCL; Scroll the display 4 pixels left
+    ScrollLeft,
+    // EXIT; Exit the interpreter
+    Exit,
+    // LOW; Switch to the 64x32 low resolution mode
+    LowRes,
+    // HIGH; Switch to the 128x64 high resolution mode
+    HighRes,
+    // SYS addr; Run the CDP1802 machine code routine at addr
+    MachineCall(Opcode),
+
     // JP addr; Jump to location addr
     Jump(Opcode),
     // CALL addr; Call subroutine at addr
@@ -51,6 +68,13 @@ pub enum Instruction {
     // SNE Vx, Vy; Skip next instruction if Vx != Vy
     SkipOnNotEqual(Opcode),
 
+    // SAVE Vx - Vy; Store registers Vx through Vy in memory
+    // starting at location I
+    StoreRange(Opcode),
+    // LOAD Vx - Vy; Read registers Vx through Vy from memory
+    // starting at location I
+    ReadRange(Opcode),
+
     // LD Vx, byte; Set Vx = byte
     SetByte(Opcode),
     //  ADD Vx, byte; Set Vx = Vx + byte
@@ -80,6 +104,8 @@ pub enum Instruction {
 
     // LD I, addr; Set I = addr
     SetI(Opcode),
+    // LD I, long addr; Set I = the 16 bit address in the next word
+    LongSetI,
 
     // JP V0, addr; Jump to location addr + V0.
     JumpPlus(Opcode),
@@ -88,7 +114,8 @@ pub enum Instruction {
     RandomMask(Opcode),
 
     // DRW Vx, Vy, nibble; Display nibble-byte sprite starting
-    // at memory location I at (Vx, Vy), set VF = collision
+    // at memory location I at (Vx, Vy), set VF = collision.
+    // A nibble of 0 draws a 16x16 sprite.
     Draw(Opcode),
 
     // SKP Vx; Skip next instruction if key with
@@ -110,6 +137,14 @@ pub enum Instruction {
     AddI(Opcode),
     // LD F, Vx; Set I = location of sprite for digit Vx
     SetSprite(Opcode),
+    // LD HF, Vx; Set I = location of the big sprite for digit Vx
+    SetBigSprite(Opcode),
+    // PLANE nibble; Select the bitplanes used by draw, clear and scroll
+    SelectPlane(Opcode),
+    // AUDIO; Load the 16-byte audio pattern at location I
+    LoadAudio,
+    // PITCH Vx; Set the audio playback pitch = Vx
+    SetPitch(Opcode),
     // LD B, Vx; Store BCD representation of Vx
     // in memory locations I, I+1, and I+2.
     Bcd(Opcode),
@@ -119,6 +154,10 @@ pub enum Instruction {
     // LD Vx, [I]; Read registers V0 through Vx in memory
     // starting at location I
     Read(Opcode),
+    // LD R, Vx; Store registers V0 through Vx in the RPL user flags
+    StoreFlags(Opcode),
+    // LD Vx, R; Read registers V0 through Vx from the RPL user flags
+    ReadFlags(Opcode),
 }
 
 impl Instruction {
@@ -128,12 +167,22 @@ impl Instruction {
         match opcode {
             Opcode { bytes: 0x00E0, .. } => Some(Instruction::Clear),
             Opcode { bytes: 0x00EE, .. } => Some(Instruction::Return),
+            Opcode { bytes: 0x00FB, .. } => Some(Instruction::ScrollRight),
+            Opcode { bytes: 0x00FC, .. } => Some(Instruction::ScrollLeft),
+            Opcode { bytes: 0x00FD, .. } => Some(Instruction::Exit),
+            Opcode { bytes: 0x00FE, .. } => Some(Instruction::LowRes),
+            Opcode { bytes: 0x00FF, .. } => Some(Instruction::HighRes),
+            Opcode { id: 0x0, x: 0x0, y: 0xC, .. } => Some(Instruction::ScrollDown(opcode)),
+            Opcode { id: 0x0, x: 0x0, y: 0xD, .. } => Some(Instruction::ScrollUp(opcode)),
+            Opcode { id: 0x0, .. } => Some(Instruction::MachineCall(opcode)),
 
             Opcode { id: 0x1, .. } => Some(Instruction::Jump(opcode)),
             Opcode { id: 0x2, .. } => Some(Instruction::Call(opcode)),
             Opcode { id: 0x3, .. } => Some(Instruction::SkipOnEqualByte(opcode)),
             Opcode { id: 0x4, .. } => Some(Instruction::SkipOnNotEqualByte(opcode)),
-            Opcode { id: 0x5, .. } => Some(Instruction::SkipOnEqual(opcode)),
+            Opcode { id: 0x5, nibble: 0x0, .. } => Some(Instruction::SkipOnEqual(opcode)),
+            Opcode { id: 0x5, nibble: 0x2, .. } => Some(Instruction::StoreRange(opcode)),
+            Opcode { id: 0x5, nibble: 0x3, .. } => Some(Instruction::ReadRange(opcode)),
             Opcode { id: 0x6, .. } => Some(Instruction::SetByte(opcode)),
             Opcode { id: 0x7, .. } => Some(Instruction::AddByte(opcode)),
 
@@ -160,6 +209,9 @@ impl Instruction {
                 Some(Instruction::SkipOnKeyNotPressed(opcode))
             }
 
+            Opcode { bytes: 0xF000, .. } => Some(Instruction::LongSetI),
+            Opcode { bytes: 0xF002, .. } => Some(Instruction::LoadAudio),
+            Opcode { id: 0xF, data: 0x01, .. } => Some(Instruction::SelectPlane(opcode)),
             Opcode { id: 0xF, y: 0x0, nibble: 0x7, .. } => {
                 Some(Instruction::StoreDelayTimer(opcode))
             }
@@ -168,9 +220,13 @@ impl Instruction {
             Opcode { id: 0xF, y: 0x1, nibble: 0x8, .. } => Some(Instruction::SetSoundTimer(opcode)),
             Opcode { id: 0xF, y: 0x1, nibble: 0xE, .. } => Some(Instruction::AddI(opcode)),
             Opcode { id: 0xF, y: 0x2, nibble: 0x9, .. } => Some(Instruction::SetSprite(opcode)),
+            Opcode { id: 0xF, y: 0x3, nibble: 0x0, .. } => Some(Instruction::SetBigSprite(opcode)),
             Opcode { id: 0xF, y: 0x3, nibble: 0x3, .. } => Some(Instruction::Bcd(opcode)),
+            Opcode { id: 0xF, y: 0x3, nibble: 0xA, .. } => Some(Instruction::SetPitch(opcode)),
             Opcode { id: 0xF, y: 0x5, nibble: 0x5, .. } => Some(Instruction::Store(opcode)),
             Opcode { id: 0xF, y: 0x6, nibble: 0x5, .. } => Some(Instruction::Read(opcode)),
+            Opcode { id: 0xF, y: 0x7, nibble: 0x5, .. } => Some(Instruction::StoreFlags(opcode)),
+            Opcode { id: 0xF, y: 0x8, nibble: 0x5, .. } => Some(Instruction::ReadFlags(opcode)),
 
             _ => None,
         }
@@ -211,6 +267,76 @@ mod tests {
         assert_eq!(Instruction::Return, instruction);
     }
 
+    #[test]
+    fn it_decodes_machine_call() {
+        let bytes: u16 = 0x0123;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::MachineCall(Opcode::new(bytes)), instruction);
+    }
+
+    #[test]
+    fn it_decodes_scroll_down() {
+        let bytes: u16 = 0x00C4;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::ScrollDown(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_decodes_scroll_up() {
+        let bytes: u16 = 0x00D4;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::ScrollUp(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_decodes_scroll_right() {
+        let bytes: u16 = 0x00FB;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::ScrollRight, instruction);
+    }
+
+    #[test]
+    fn it_decodes_scroll_left() {
+        let bytes: u16 = 0x00FC;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::ScrollLeft, instruction);
+    }
+
+    #[test]
+    fn it_decodes_exit() {
+        let bytes: u16 = 0x00FD;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::Exit, instruction);
+    }
+
+    #[test]
+    fn it_decodes_low_res() {
+        let bytes: u16 = 0x00FE;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::LowRes, instruction);
+    }
+
+    #[test]
+    fn it_decodes_high_res() {
+        let bytes: u16 = 0x00FF;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::HighRes, instruction);
+    }
+
     #[test]
     fn it_decodes_jump() {
         let bytes: u16 = 0x1A1E;
@@ -266,6 +392,33 @@ mod tests {
         assert_eq!(expected, instruction);
     }
 
+    #[test]
+    fn it_decodes_store_range() {
+        let bytes: u16 = 0x51F2;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::StoreRange(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_decodes_read_range() {
+        let bytes: u16 = 0x51F3;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::ReadRange(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_does_not_decode_unknown_5xyn_instructions() {
+        assert_eq!(None, Instruction::decode(0x51F1));
+    }
+
     #[test]
     fn it_decodes_set_byte() {
         let bytes: u16 = 0x61FA;
@@ -409,6 +562,14 @@ mod tests {
         assert_eq!(expected, instruction);
     }
 
+    #[test]
+    fn it_decodes_long_set_i() {
+        let bytes: u16 = 0xF000;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::LongSetI, instruction);
+    }
+
     #[test]
     fn it_decodes_jump_plus() {
         let bytes: u16 = 0xB1AF;
@@ -530,6 +691,47 @@ mod tests {
         assert_eq!(expected, instruction);
     }
 
+    #[test]
+    fn it_decodes_set_big_font() {
+        let bytes: u16 = 0xF230;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::SetBigSprite(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_decodes_select_plane() {
+        let bytes: u16 = 0xF201;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::SelectPlane(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_decodes_load_audio() {
+        let bytes: u16 = 0xF002;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        assert_eq!(Instruction::LoadAudio, instruction);
+    }
+
+    #[test]
+    fn it_decodes_set_pitch() {
+        let bytes: u16 = 0xF23A;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::SetPitch(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
     #[test]
     fn it_decodes_bcd() {
         let bytes: u16 = 0xF233;
@@ -562,4 +764,26 @@ mod tests {
 
         assert_eq!(expected, instruction);
     }
+
+    #[test]
+    fn it_decodes_store_flags() {
+        let bytes: u16 = 0xF275;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::StoreFlags(opcode);
+
+        assert_eq!(expected, instruction);
+    }
+
+    #[test]
+    fn it_decodes_read_flags() {
+        let bytes: u16 = 0xF285;
+        let instruction = Instruction::decode(bytes).unwrap();
+
+        let opcode = Opcode::new(bytes);
+        let expected = Instruction::ReadFlags(opcode);
+
+        assert_eq!(expected, instruction);
+    }
 }
@@ -1,6 +1,5 @@
 // Trying to emulate a chip-8 computer!
 extern crate rand;
-extern crate sdl2;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
@@ -8,8 +7,17 @@ extern crate env_logger;
 
 pub mod specs;
 pub mod instructions;
+pub mod quirks;
+pub mod timing;
+pub mod random;
+pub mod clock;
+pub mod memory;
+pub mod cdp1802;
 pub mod keypad;
 pub mod display;
+pub mod image;
+pub mod terminal;
+pub mod audio;
 pub mod vm;
 
 /// Returns the version of this crate in the format `MAJOR.MINOR.PATCH`.
@@ -0,0 +1,361 @@
+// Memory bus
+//
+// Every read and write the program does goes through the `Bus`, so accesses
+// can be watched, counted or sent to memory-mapped devices. Loaders, save
+// states and debuggers poke the RAM directly with `bus[addr]`, which doesn't
+// trigger any hook.
+
+use std::collections::VecDeque;
+use std::ops::{Index, IndexMut, Range};
+
+// The most watchpoint hits kept until someone takes them. Older hits are
+// dropped first, so a watchpoint nobody polls doesn't grow without bound.
+pub const MAX_HITS: usize = 1024;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Access {
+    // The CPU read an instruction
+    Fetch,
+    Read,
+    Write,
+}
+
+/// Anything that can be addressed byte by byte.
+pub trait Memory {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read(&mut self, addr: usize) -> u8;
+
+    fn write(&mut self, addr: usize, value: u8);
+}
+
+impl Memory for Vec<u8> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn read(&mut self, addr: usize) -> u8 {
+        self[addr]
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        self[addr] = value;
+    }
+}
+
+/// Called with every access: kind, address and value read or written.
+pub type Hook = Box<dyn FnMut(Access, usize, u8) + Send>;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Watchpoint {
+    pub range: Range<usize>,
+    pub access: Access,
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Hit {
+    pub access: Access,
+    pub address: usize,
+    pub value: u8,
+}
+
+// A device mapped over a range of addresses.
+struct Region {
+    start: usize,
+    device: Box<dyn Memory + Send>,
+}
+
+impl Region {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.start + self.device.len()
+    }
+}
+
+pub struct Bus {
+    ram: Vec<u8>,
+    regions: Vec<Region>,
+    hooks: Vec<Hook>,
+    watchpoints: Vec<Watchpoint>,
+    hits: VecDeque<Hit>,
+    counters: Option<(Vec<u64>, Vec<u64>)>, // Reads and writes per address
+}
+
+impl Bus {
+    pub fn new(size: usize) -> Bus {
+        Bus {
+            ram: vec![0; size],
+            regions: vec![],
+            hooks: vec![],
+            watchpoints: vec![],
+            hits: VecDeque::new(),
+            counters: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ram.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ram.is_empty()
+    }
+
+    pub fn resize(&mut self, size: usize) {
+        self.ram.resize(size, 0);
+        if let Some((ref mut reads, ref mut writes)) = self.counters {
+            reads.resize(size, 0);
+            writes.resize(size, 0);
+        }
+    }
+
+    /// The RAM behind the bus. Mapped devices are not included.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    /// True when accesses have no side effects: no hooks, watchpoints,
+    /// counters or mapped devices.
+    pub fn is_plain(&self) -> bool {
+        self.regions.is_empty() && self.hooks.is_empty() && self.watchpoints.is_empty() &&
+        self.counters.is_none()
+    }
+
+    /// Reads an instruction byte.
+    pub fn fetch(&mut self, addr: usize) -> u8 {
+        self.access(Access::Fetch, addr, None)
+    }
+
+    /// Sends the accesses in `start..start + device.len()` to the device
+    /// instead of the RAM.
+    pub fn map(&mut self, start: usize, device: Box<dyn Memory + Send>) {
+        self.regions.push(Region { start, device });
+    }
+
+    pub fn add_hook(&mut self, hook: Hook) {
+        self.hooks.push(hook);
+    }
+
+    pub fn watch(&mut self, range: Range<usize>, access: Access) {
+        self.watchpoints.push(Watchpoint { range, access });
+    }
+
+    pub fn unwatch(&mut self, range: Range<usize>, access: Access) {
+        self.watchpoints.retain(|w| w.range != range || w.access != access);
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns the accesses that matched a watchpoint since the last call,
+    /// the last `MAX_HITS` of them.
+    pub fn take_hits(&mut self) -> Vec<Hit> {
+        self.hits.drain(..).collect()
+    }
+
+    /// Starts or stops counting the accesses to each address. Stopping
+    /// the counters resets them.
+    pub fn count_accesses(&mut self, enabled: bool) {
+        self.counters = if enabled {
+            Some((vec![0; self.ram.len()], vec![0; self.ram.len()]))
+        } else {
+            None
+        };
+    }
+
+    /// How many times the address was read or fetched.
+    pub fn reads(&self, addr: usize) -> u64 {
+        match self.counters {
+            Some((ref reads, _)) => reads.get(addr).cloned().unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    pub fn writes(&self, addr: usize) -> u64 {
+        match self.counters {
+            Some((_, ref writes)) => writes.get(addr).cloned().unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    fn access(&mut self, access: Access, addr: usize, value: Option<u8>) -> u8 {
+        let value = match self.regions.iter_mut().find(|r| r.contains(addr)) {
+            Some(region) => {
+                match value {
+                    Some(value) => {
+                        region.device.write(addr - region.start, value);
+                        value
+                    }
+                    None => region.device.read(addr - region.start),
+                }
+            }
+            None => {
+                match value {
+                    Some(value) => {
+                        self.ram[addr] = value;
+                        value
+                    }
+                    None => self.ram[addr],
+                }
+            }
+        };
+
+        if let Some((ref mut reads, ref mut writes)) = self.counters {
+            let counter = match access {
+                Access::Write => writes.get_mut(addr),
+                _ => reads.get_mut(addr),
+            };
+            if let Some(counter) = counter {
+                *counter += 1;
+            }
+        }
+
+        for w in &self.watchpoints {
+            if w.access == access && w.range.start <= addr && addr < w.range.end {
+                if self.hits.len() == MAX_HITS {
+                    self.hits.pop_front();
+                }
+                self.hits.push_back(Hit { access, address: addr, value });
+            }
+        }
+
+        for hook in &mut self.hooks {
+            hook(access, addr, value);
+        }
+
+        value
+    }
+}
+
+impl Memory for Bus {
+    fn len(&self) -> usize {
+        self.ram.len()
+    }
+
+    fn read(&mut self, addr: usize) -> u8 {
+        self.access(Access::Read, addr, None)
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        self.access(Access::Write, addr, Some(value));
+    }
+}
+
+impl Index<usize> for Bus {
+    type Output = u8;
+
+    fn index(&self, addr: usize) -> &u8 {
+        &self.ram[addr]
+    }
+}
+
+impl IndexMut<usize> for Bus {
+    fn index_mut(&mut self, addr: usize) -> &mut u8 {
+        &mut self.ram[addr]
+    }
+}
+
+impl Index<Range<usize>> for Bus {
+    type Output = [u8];
+
+    fn index(&self, range: Range<usize>) -> &[u8] {
+        &self.ram[range]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn it_reads_and_writes_the_ram() {
+        let mut bus = Bus::new(16);
+
+        bus.write(0x4, 0xAB);
+
+        assert_eq!(0xAB, bus.read(0x4));
+        assert_eq!(0xAB, bus[0x4]);
+    }
+
+    #[test]
+    fn it_records_watchpoint_hits() {
+        let mut bus = Bus::new(16);
+        bus.watch(0x4..0x6, Access::Write);
+
+        bus.write(0x3, 0x1);
+        bus.write(0x5, 0x2);
+        bus.read(0x5);
+
+        assert_eq!(vec![Hit { access: Access::Write, address: 0x5, value: 0x2 }],
+                   bus.take_hits());
+        assert!(bus.take_hits().is_empty());
+    }
+
+    #[test]
+    fn it_keeps_only_the_last_hits() {
+        let mut bus = Bus::new(16);
+        bus.watch(0x0..0x1, Access::Read);
+
+        for _ in 0..MAX_HITS + 10 {
+            bus.read(0x0);
+        }
+        bus.write(0x0, 0x7);
+        bus.read(0x0);
+
+        let hits = bus.take_hits();
+        assert_eq!(MAX_HITS, hits.len());
+        assert_eq!(0x7, hits[MAX_HITS - 1].value);
+    }
+
+    #[test]
+    fn it_counts_accesses() {
+        let mut bus = Bus::new(16);
+        bus.count_accesses(true);
+
+        bus.write(0x2, 0x1);
+        bus.read(0x2);
+        bus.fetch(0x2);
+
+        assert_eq!(2, bus.reads(0x2));
+        assert_eq!(1, bus.writes(0x2));
+        assert_eq!(0, bus.reads(0x3));
+    }
+
+    #[test]
+    fn it_calls_the_hooks() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let accesses = log.clone();
+
+        let mut bus = Bus::new(16);
+        bus.add_hook(Box::new(move |access, addr, value| {
+            accesses.lock().unwrap().push((access, addr, value))
+        }));
+
+        bus.write(0x1, 0xF);
+        bus.fetch(0x1);
+
+        assert_eq!(vec![(Access::Write, 0x1, 0xF), (Access::Fetch, 0x1, 0xF)],
+                   *log.lock().unwrap());
+    }
+
+    #[test]
+    fn it_maps_devices_over_the_ram() {
+        let mut bus = Bus::new(16);
+        bus.map(0x8, Box::new(vec![0xEE; 4]));
+
+        bus.write(0x9, 0x1);
+
+        assert_eq!(0x1, bus.read(0x9));
+        assert_eq!(0xEE, bus.read(0xA));
+        assert_eq!(0x0, bus[0x9]); // The RAM below is untouched
+    }
+}
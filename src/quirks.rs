@@ -0,0 +1,112 @@
+// Behaviours that changed between the different CHIP-8 interpreters.
+//
+// Each platform interpreted a handful of opcodes in its own way, and ROMs
+// written for one of them can break on the others. A `Quirks` value tells
+// the VM which interpretation to use.
+
+/// How much `I` is incremented by FX55 (store) and FX65 (read).
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum MemoryIncrement {
+    // I is left untouched.
+    None,
+    // I = I + X
+    X,
+    // I = I + X + 1
+    XPlusOne,
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift Vy and store the result in Vx. Otherwise Vx is
+    // shifted in place and Vy is ignored.
+    pub shift_vy: bool,
+    // How FX55/FX65 leave I after the transfer.
+    pub memory_increment: MemoryIncrement,
+    // BXNN jumps to XNN + Vx instead of NNN + V0.
+    pub jump_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0.
+    pub vf_reset: bool,
+    // Sprites are clipped at the edges of the screen instead of wrapping
+    // around to the other side.
+    pub clip_sprites: bool,
+    // DXYN waits for the vertical blank, so there is at most one draw
+    // per frame.
+    pub display_wait: bool,
+}
+
+pub const COSMAC_VIP: Quirks = Quirks {
+    shift_vy: true,
+    memory_increment: MemoryIncrement::XPlusOne,
+    jump_vx: false,
+    vf_reset: true,
+    clip_sprites: true,
+    display_wait: true,
+};
+
+pub const CHIP_48: Quirks = Quirks {
+    shift_vy: false,
+    memory_increment: MemoryIncrement::X,
+    jump_vx: true,
+    vf_reset: false,
+    clip_sprites: true,
+    display_wait: false,
+};
+
+pub const SUPER_CHIP: Quirks = Quirks {
+    shift_vy: false,
+    memory_increment: MemoryIncrement::None,
+    jump_vx: true,
+    vf_reset: false,
+    clip_sprites: true,
+    display_wait: false,
+};
+
+pub const XO_CHIP: Quirks = Quirks {
+    shift_vy: true,
+    memory_increment: MemoryIncrement::XPlusOne,
+    jump_vx: false,
+    vf_reset: false,
+    clip_sprites: false,
+    display_wait: false,
+};
+
+/// Returns the preset with the given name, if any.
+///
+/// Valid names are `vip`, `chip48`, `schip` and `xochip`.
+pub fn preset(name: &str) -> Option<Quirks> {
+    match name {
+        "vip" => Some(COSMAC_VIP),
+        "chip48" => Some(CHIP_48),
+        "schip" => Some(SUPER_CHIP),
+        "xochip" => Some(XO_CHIP),
+        _ => None,
+    }
+}
+
+impl Default for Quirks {
+    // The way this interpreter has always behaved.
+    fn default() -> Quirks {
+        Quirks {
+            shift_vy: true,
+            memory_increment: MemoryIncrement::XPlusOne,
+            jump_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_finds_presets_by_name() {
+        assert_eq!(Some(COSMAC_VIP), preset("vip"));
+        assert_eq!(Some(CHIP_48), preset("chip48"));
+        assert_eq!(Some(SUPER_CHIP), preset("schip"));
+        assert_eq!(Some(XO_CHIP), preset("xochip"));
+        assert_eq!(None, preset("eti660"));
+    }
+}
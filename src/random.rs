@@ -0,0 +1,100 @@
+// Random numbers for CXNN.
+//
+// The VM owns its random source, so a run can be repeated by seeding it
+// with the same value, and its state can be saved along with the VM.
+
+use rand::{thread_rng, Rng};
+
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+
+    /// Returns the internal state of the source, if it can be saved.
+    fn state(&self) -> Option<u64> {
+        None
+    }
+
+    /// Restores a state previously returned by `state`.
+    fn set_state(&mut self, _state: u64) {}
+}
+
+/// SplitMix64 generator. Small, fast and every 64 bit state is valid,
+/// which makes it trivial to save and restore.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct SplitMix {
+    state: u64,
+}
+
+impl SplitMix {
+    pub fn new(seed: u64) -> SplitMix {
+        SplitMix { state: seed }
+    }
+
+    /// Seeds the generator from the thread's random number generator.
+    pub fn from_entropy() -> SplitMix {
+        SplitMix::new(thread_rng().gen())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SplitMix {
+    fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn state(&self) -> Option<u64> {
+        Some(self.state)
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_repeats_the_sequence_with_the_same_seed() {
+        let mut a = SplitMix::new(42);
+        let mut b = SplitMix::new(42);
+
+        let a: Vec<u8> = (0..32).map(|_| a.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_byte()).collect();
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn it_changes_the_sequence_with_another_seed() {
+        let mut a = SplitMix::new(42);
+        let mut b = SplitMix::new(43);
+
+        let a: Vec<u8> = (0..32).map(|_| a.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_byte()).collect();
+
+        assert!(a != b);
+    }
+
+    #[test]
+    fn it_restores_its_state() {
+        let mut rng = SplitMix::new(7);
+        rng.next_byte();
+
+        let state = rng.state().unwrap();
+        let expected: Vec<u8> = (0..8).map(|_| rng.next_byte()).collect();
+
+        rng.set_state(state);
+        let actual: Vec<u8> = (0..8).map(|_| rng.next_byte()).collect();
+
+        assert_eq!(expected, actual);
+    }
+}
@@ -1,7 +1,11 @@
 pub const RAM_SIZE: usize = 4096;
+pub const XO_RAM_SIZE: usize = 65536;
 pub const GENERAL_REGISTERS_SIZE: usize = 16;
 pub const STACK_SIZE: usize = 16;
 pub const PROGRAM_START: usize = 512; // 0x200
+pub const ETI_660_PROGRAM_START: usize = 1536; // 0x600
+pub const HIRES_CHIP8_JUMP: usize = 608; // 0x260, the first instruction of HiRes ROMs jumps here
+pub const HIRES_CHIP8_START: usize = 704; // 0x2C0, where HiRes ROMs really start
 
 pub const SPRITE_HEIGHT: usize = 5;
 pub const SPRITES_SIZE: usize = SPRITE_HEIGHT * 16;
@@ -25,11 +29,45 @@ pub const SPRITES: [u8; SPRITES_SIZE] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+pub const BIG_SPRITE_HEIGHT: usize = 10;
+pub const BIG_SPRITES_SIZE: usize = BIG_SPRITE_HEIGHT * 16;
+pub const BIG_SPRITES_ADDR: usize = SPRITES_ADDR + SPRITES_SIZE;
+pub const BIG_SPRITES: [u8; BIG_SPRITES_SIZE] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+pub const FLAGS_SIZE: usize = 16; // RPL user flags, 8 on SUPER-CHIP
+
+pub const AUDIO_PATTERN_SIZE: usize = 16; // 128 1-bit samples
+pub const DEFAULT_PITCH: u8 = 64; // 4000 Hz playback rate
+
 pub const KEYPAD_SIZE: usize = 16;
 
-pub const CLOCK: u64 = 16; // Milliseconds: 1000 ms / 60
+pub const TIMER_FREQUENCY: u64 = 60; // Hz, delay and sound timers
+pub const CPU_FREQUENCY: u64 = 600; // Hz, instructions per second
+pub const REWIND_FRAMES: usize = 30 * TIMER_FREQUENCY as usize; // 30 seconds of frames
 
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 pub const DISPLAY_PIXELS: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
-pub const DISPLAY_SCALE: usize = 20;
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+pub const HIRES_DISPLAY_PIXELS: usize = HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT;
+pub const HIRES_CHIP8_DISPLAY_WIDTH: usize = 64;
+pub const HIRES_CHIP8_DISPLAY_HEIGHT: usize = 64;
+pub const DISPLAY_SCALE: usize = 20; // Applies to the low resolution mode
@@ -0,0 +1,153 @@
+// Terminal output, to play over SSH or anywhere without a window.
+//
+// Each character cell shows two pixels with the upper half block: the top
+// pixel is the foreground color and the bottom one the background. A 64x32
+// screen takes 64x16 cells and a 128x64 one 128x32. Reading the keys is up
+// to the host, like it is for windows.
+
+use std::io::Write;
+
+use audio::AudioSink;
+use display::{self, Disconnected, DisplaySink, Frame, Rgb};
+
+const UPPER_HALF: char = '\u{2580}';
+
+/// Draws the frames with ANSI escape codes on any writer, usually stdout.
+pub struct TerminalDisplay<W: Write> {
+    out: W,
+    size: (usize, usize),
+}
+
+impl<W: Write> TerminalDisplay<W> {
+    pub fn new(out: W) -> TerminalDisplay<W> {
+        TerminalDisplay { out, size: (0, 0) }
+    }
+}
+
+impl<W: Write> DisplaySink for TerminalDisplay<W> {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected> {
+        let mut screen = String::new();
+
+        let mut area = frame.dirty;
+        if self.size != (frame.width, frame.height) {
+            self.size = (frame.width, frame.height);
+            area = display::Rect::new(0, 0, frame.width, frame.height);
+            // Hide the cursor and start from a blank terminal
+            screen.push_str("\x1b[?25l\x1b[0m\x1b[2J");
+        }
+
+        if area.width > 0 && area.height > 0 {
+            for row in area.y / 2..(area.y + area.height).div_ceil(2) {
+                screen.push_str(&format!("\x1b[{};{}H", row + 1, area.x + 1));
+
+                let mut colors = None;
+                for x in area.x..area.x + area.width {
+                    let top = display::color(frame.pixel(x, row * 2));
+                    let bottom = if row * 2 + 1 < frame.height {
+                        display::color(frame.pixel(x, row * 2 + 1))
+                    } else {
+                        display::color(0)
+                    };
+
+                    // Neighbours often share colors, no need to repeat them
+                    if colors != Some((top, bottom)) {
+                        colors = Some((top, bottom));
+                        screen.push_str(&format!("\x1b[38;2;{}m\x1b[48;2;{}m",
+                                                 ansi(top),
+                                                 ansi(bottom)));
+                    }
+                    screen.push(UPPER_HALF);
+                }
+            }
+            screen.push_str("\x1b[0m");
+        }
+
+        self.out
+            .write_all(screen.as_bytes())
+            .and_then(|_| self.out.flush())
+            .map_err(|_| Disconnected)
+    }
+}
+
+impl<W: Write> Drop for TerminalDisplay<W> {
+    fn drop(&mut self) {
+        // Leave the prompt below the screen, with the cursor back
+        let rows = self.size.1.div_ceil(2);
+        let _ = write!(self.out, "\x1b[0m\x1b[{};1H\x1b[?25h", rows + 1);
+        let _ = self.out.flush();
+    }
+}
+
+fn ansi(color: Rgb) -> String {
+    format!("{};{};{}", color.r, color.g, color.b)
+}
+
+/// Rings the terminal bell when the beep starts. Terminals can't hold a
+/// tone, so the pattern and its length are lost.
+pub struct Bell<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Bell<W> {
+    pub fn new(out: W) -> Bell<W> {
+        Bell { out }
+    }
+}
+
+impl<W: Write> AudioSink for Bell<W> {
+    fn start(&mut self, _pattern: &[u8], _rate: f64) {
+        let _ = self.out.write_all(b"\x07").and_then(|_| self.out.flush());
+    }
+
+    fn stop(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_draws_two_pixels_per_cell() {
+        let mut out = vec![];
+        {
+            let mut display = TerminalDisplay::new(&mut out);
+            let mut frame = Frame::new(2, 2);
+            frame.pixels[1] = 1; // Top right
+            display.present(&frame).unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("\x1b[2J"));
+        assert!(out.contains("\x1b[1;1H\x1b[38;2;0;0;0m\x1b[48;2;0;0;0m\u{2580}\
+                              \x1b[38;2;255;255;255m\x1b[48;2;0;0;0m\u{2580}"));
+        assert!(out.ends_with("\x1b[?25h"));
+    }
+
+    #[test]
+    fn it_redraws_only_the_rows_that_changed() {
+        let mut display = TerminalDisplay::new(vec![]);
+        let mut frame = Frame::default();
+        display.present(&frame).unwrap();
+        display.out.clear();
+
+        frame.dirty = display::Rect::new(3, 5, 2, 2);
+        display.present(&frame).unwrap();
+        let out = String::from_utf8(display.out.clone()).unwrap();
+
+        // Pixel rows 5 and 6 are on the cell rows 3 and 4
+        assert!(!out.contains("\x1b[2J"));
+        assert!(out.contains("\x1b[3;4H"));
+        assert!(out.contains("\x1b[4;4H"));
+        assert!(!out.contains("\x1b[5;4H"));
+    }
+
+    #[test]
+    fn the_bell_rings_when_the_beep_starts() {
+        let mut bell = Bell::new(vec![]);
+
+        bell.start(&[0; 16], 4000.0);
+        bell.stop();
+
+        assert_eq!(b"\x07".to_vec(), bell.out);
+    }
+}
@@ -0,0 +1,109 @@
+// How long instructions take.
+//
+// By default every instruction costs the same and the CPU runs at a fixed
+// number of instructions per second. The COSMAC VIP interpreter was much
+// less regular: a register load took a few dozen machine cycles of its
+// CDP1802 while a sprite could take thousands. The `Vip` model charges
+// each instruction its approximate cost on the VIP, so ROMs written for it
+// run at their original speed.
+
+use instructions::Instruction;
+
+#[derive(Debug,Copy,Clone,PartialEq,Default)]
+pub enum Timing {
+    // Every instruction costs the same, see `VM::set_cpu_frequency`.
+    #[default]
+    Fixed,
+    // Instructions cost their COSMAC VIP machine cycles.
+    Vip,
+}
+
+// Machine cycles (8 clock periods of the 1.76 MHz CDP1802) in a 60 Hz frame.
+pub const VIP_CYCLES_PER_FRAME: i64 = 3668;
+// Cycles of each frame taken by the display DMA and its interrupt routine.
+pub const VIP_DISPLAY_CYCLES: i64 = 1100;
+
+// Every instruction pays for its fetch and decode.
+const FETCH: i64 = 40;
+
+/// Returns how many VIP machine cycles the instruction takes. `registers`
+/// are the V registers before the instruction runs, some costs depend on
+/// them.
+pub fn vip_cycles(instruction: &Instruction, registers: &[u8]) -> i64 {
+    let execute = match *instruction {
+        Instruction::Clear => 3024,
+        Instruction::Return => 10,
+        Instruction::Jump(_) => 12,
+        Instruction::Call(_) => 26,
+        // The routine itself is charged as it runs
+        Instruction::MachineCall(_) => 16,
+
+        Instruction::SkipOnEqualByte(_) |
+        Instruction::SkipOnNotEqualByte(_) => 14,
+        Instruction::SkipOnEqual(_) |
+        Instruction::SkipOnNotEqual(_) => 18,
+
+        Instruction::SetByte(_) => 6,
+        Instruction::AddByte(_) => 10,
+        Instruction::Set(_) |
+        Instruction::Or(_) |
+        Instruction::And(_) |
+        Instruction::Xor(_) |
+        Instruction::Add(_) |
+        Instruction::SubXY(_) |
+        Instruction::SubYX(_) |
+        Instruction::ShiftRight(_) |
+        Instruction::ShiftLeft(_) => 44,
+
+        Instruction::SetI(_) => 12,
+        Instruction::JumpPlus(_) => 22,
+        Instruction::RandomMask(_) => 36,
+
+        // The sprite is shifted into place row by row, which is
+        // slower when it isn't aligned to a byte of the screen.
+        Instruction::Draw(opcode) => {
+            let rows = opcode.nibble as i64;
+            let aligned = registers[opcode.x as usize] & 0b111 == 0;
+            let row = if aligned { 38 } else { 68 };
+
+            26 + rows * row
+        }
+
+        Instruction::SkipOnKeyPressed(_) |
+        Instruction::SkipOnKeyNotPressed(_) => 18,
+
+        Instruction::StoreDelayTimer(_) |
+        Instruction::SetDelayTimer(_) |
+        Instruction::SetSoundTimer(_) => 10,
+        Instruction::WaitKey(_) => 18,
+        Instruction::AddI(_) => 16,
+        Instruction::SetSprite(_) => 20,
+        Instruction::Bcd(opcode) => 80 + registers[opcode.x as usize] as i64 / 10 * 8,
+        Instruction::Store(opcode) |
+        Instruction::Read(opcode) => 14 + (opcode.x as i64 + 1) * 14,
+
+        // Not part of the VIP interpreter, they get the cost of
+        // a register operation.
+        _ => 44,
+    };
+
+    FETCH + execute
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_charges_more_for_unaligned_sprites() {
+        let draw = Instruction::decode(0xD015).unwrap();
+        let mut registers = [0; 16];
+
+        let aligned = vip_cycles(&draw, &registers);
+        registers[0x0] = 3;
+        let unaligned = vip_cycles(&draw, &registers);
+
+        assert_eq!(FETCH + 26 + 5 * 38, aligned);
+        assert!(unaligned > aligned);
+    }
+}
@@ -0,0 +1,212 @@
+// Terminal frontend of the binary: stdin is the keypad and the screen is
+// drawn with the terminal renderer of the library.
+//
+// Terminals only tell when a key is typed, never when it's released. A key
+// stays down for a moment after each press and the key repeat of the
+// terminal keeps it down while it's held.
+
+use johnny_eight::keypad::Key;
+use johnny_eight::specs;
+use johnny_eight::terminal::{Bell, TerminalDisplay};
+use johnny_eight::vm::{VM, VmBuilder, VmError, State};
+
+use std::io::{self, Read};
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Long enough to bridge the delay before the terminal starts repeating keys.
+const KEY_HOLD: Duration = Duration::from_millis(500);
+
+// Plays in the terminal, stdin is the keypad. Errors are returned, not
+// printed, so the terminal is out of raw mode by the time they are shown.
+pub fn run_in_terminal(builder: VmBuilder) -> Result<(), VmError> {
+    let _raw_mode = RawMode::enable()?;
+    let input = stdin_bytes();
+    let mut keypad = TerminalKeypad::new();
+    let mut vm = builder.display(Box::new(TerminalDisplay::new(io::stdout())))
+        .audio(Box::new(Bell::new(io::stdout())))
+        .build()?;
+
+    loop {
+        // Wait for the next frame or key
+        let mut pending = vec![];
+        if let Some(timeout) = vm.next_frame_in() {
+            match input.recv_timeout(timeout) {
+                Ok(byte) => pending.push(byte),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+        pending.extend(input.try_iter());
+
+        let now = Instant::now();
+        keypad.release(&mut vm, now);
+        for byte in pending {
+            match byte {
+                // Esc or Ctrl-C, raw mode doesn't turn it into a signal
+                0x1b | 0x03 => return Ok(()),
+                _ => {
+                    if let Some(key) = keypad_key(byte) {
+                        keypad.press(&mut vm, key, now);
+                    }
+                }
+            }
+        }
+
+        vm.cycle()?;
+
+        if vm.state() == State::Exited {
+            return Ok(());
+        }
+    }
+}
+
+// Holds the keys typed on the terminal down for `KEY_HOLD`.
+#[derive(Debug,Default)]
+struct TerminalKeypad {
+    pressed: [Option<Instant>; specs::KEYPAD_SIZE],
+}
+
+impl TerminalKeypad {
+    fn new() -> TerminalKeypad {
+        TerminalKeypad::default()
+    }
+
+    fn press(&mut self, vm: &mut VM, key: Key, now: Instant) {
+        self.pressed[key.as_usize()] = Some(now);
+        vm.key_down(key);
+    }
+
+    // Releases the keys that weren't typed again in time.
+    fn release(&mut self, vm: &mut VM, now: Instant) {
+        for (value, pressed) in self.pressed.iter_mut().enumerate() {
+            if let Some(at) = *pressed {
+                if now.duration_since(at) >= KEY_HOLD {
+                    *pressed = None;
+                    if let Some(key) = key_from_value(value) {
+                        vm.key_up(key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn key_from_value(value: usize) -> Option<Key> {
+    match value {
+        0x0 => Some(Key::Num0),
+        0x1 => Some(Key::Num1),
+        0x2 => Some(Key::Num2),
+        0x3 => Some(Key::Num3),
+        0x4 => Some(Key::Num4),
+        0x5 => Some(Key::Num5),
+        0x6 => Some(Key::Num6),
+        0x7 => Some(Key::Num7),
+        0x8 => Some(Key::Num8),
+        0x9 => Some(Key::Num9),
+        0xA => Some(Key::A),
+        0xB => Some(Key::B),
+        0xC => Some(Key::C),
+        0xD => Some(Key::D),
+        0xE => Some(Key::E),
+        0xF => Some(Key::F),
+        _ => None,
+    }
+}
+
+// The same QWERTY layout as the window:
+//
+//   1 2 3 4      1 2 3 C
+//   Q W E R  =>  4 5 6 D
+//   A S D F      7 8 9 E
+//   Z X C V      A 0 B F
+fn keypad_key(byte: u8) -> Option<Key> {
+    match byte.to_ascii_lowercase() {
+        b'1' => Some(Key::Num1),
+        b'2' => Some(Key::Num2),
+        b'3' => Some(Key::Num3),
+        b'4' => Some(Key::C),
+        b'q' => Some(Key::Num4),
+        b'w' => Some(Key::Num5),
+        b'e' => Some(Key::Num6),
+        b'r' => Some(Key::D),
+        b'a' => Some(Key::Num7),
+        b's' => Some(Key::Num8),
+        b'd' => Some(Key::Num9),
+        b'f' => Some(Key::E),
+        b'z' => Some(Key::A),
+        b'x' => Some(Key::Num0),
+        b'c' => Some(Key::B),
+        b'v' => Some(Key::F),
+        _ => None,
+    }
+}
+
+// Keeps the terminal in raw mode, without echo, until it's dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&["sane"]);
+    }
+}
+
+// stty changes the terminal on its stdin, which is ours.
+fn stty(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("stty").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other("stty failed, is stdin a terminal?"))
+    }
+}
+
+// Reads stdin on its own thread and sends every byte typed. The thread is
+// never joined, it ends with the process.
+fn stdin_bytes() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut buffer = [0; 64];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        // Stops at the end of the input or when nobody listens anymore
+        while let Ok(count) = stdin.read(&mut buffer) {
+            if count == 0 || buffer[..count].iter().any(|&byte| sender.send(byte).is_err()) {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_keys_are_held_for_a_moment() {
+        let mut vm = VM::boot();
+        let mut keypad = TerminalKeypad::new();
+        let start = Instant::now();
+
+        keypad.press(&mut vm, keypad_key(b'W').unwrap(), start);
+        assert!(vm.is_key_down(Key::Num5));
+
+        keypad.release(&mut vm, start + KEY_HOLD / 2);
+        assert!(vm.is_key_down(Key::Num5));
+
+        keypad.release(&mut vm, start + KEY_HOLD);
+        assert!(!vm.is_key_down(Key::Num5));
+    }
+}
@@ -0,0 +1,304 @@
+// Everything needed to start a VM, in one place.
+//
+//     let mut vm = VmBuilder::new()
+//         .quirks(quirks::SUPER_CHIP)
+//         .rom(rom)
+//         .display(Box::new(NullSink))
+//         .build()?;
+//
+// `build` checks the configuration and returns a VM that is ready to run:
+// fonts and ROM in memory and a clock to drive it.
+
+use audio::AudioSink;
+use clock::{Clock, RealtimeClock};
+use display::DisplaySink;
+use quirks::Quirks;
+use specs;
+use timing::Timing;
+use vm::{VM, VmError, VmObserver, UnknownOpcodePolicy};
+
+/// The fonts loaded into memory, below the program start.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Fonts {
+    // The ROM brings its own, or doesn't need any
+    None,
+    // The 4x5 hex digits of CHIP-8
+    Chip8,
+    // The 4x5 digits and the 8x10 digits of SUPER-CHIP
+    SuperChip,
+}
+
+/// The plain settings of a VM.
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct VmConfig {
+    pub ram_size: usize,
+    pub program_start: usize, // Where the ROM is loaded
+    pub entry_point: Option<usize>, // PC on reset, the program start when not set
+    pub hires_chip8: bool, // 64x64 display, see `VM::set_hires_chip8`
+    pub fonts: Fonts,
+    pub stack_depth: usize,
+    pub quirks: Quirks,
+    pub timing: Timing,
+    pub cpu_frequency: u64, // Instructions per second with `Timing::Fixed`
+    pub seed: Option<u64>, // Entropy when not set
+    pub rewind_frames: usize,
+    pub machine_code: bool, // Run 0NNN on a CDP1802
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            ram_size: specs::RAM_SIZE,
+            program_start: specs::PROGRAM_START,
+            entry_point: None,
+            hires_chip8: false,
+            fonts: Fonts::SuperChip,
+            stack_depth: specs::STACK_SIZE,
+            quirks: Quirks::default(),
+            timing: Timing::default(),
+            cpu_frequency: specs::CPU_FREQUENCY,
+            seed: None,
+            rewind_frames: 0,
+            machine_code: false,
+        }
+    }
+}
+
+impl VmConfig {
+    /// ETI-660 programs are loaded at 0x600.
+    pub fn eti_660() -> VmConfig {
+        VmConfig { program_start: specs::ETI_660_PROGRAM_START, ..VmConfig::default() }
+    }
+
+    /// HiRes CHIP-8 programs draw on a 64x64 display.
+    pub fn hires_chip8() -> VmConfig {
+        VmConfig { hires_chip8: true, ..VmConfig::default() }
+    }
+
+    /// Returns the first mistake found in the settings, if any.
+    pub fn validate(&self) -> Result<(), VmError> {
+        if self.ram_size > specs::XO_RAM_SIZE {
+            return Err(VmError::InvalidConfig("the RAM can't be larger than 64KB"));
+        }
+        if self.program_start >= self.ram_size {
+            return Err(VmError::InvalidConfig("the program starts outside of the RAM"));
+        }
+        if let Some(entry_point) = self.entry_point {
+            if entry_point + 1 >= self.ram_size {
+                return Err(VmError::InvalidConfig("the entry point is outside of the RAM"));
+            }
+        }
+        if self.program_start & 1 != 0 {
+            return Err(VmError::InvalidConfig("the program must start at an even address"));
+        }
+        if self.program_start < self.fonts_size() {
+            return Err(VmError::InvalidConfig("the fonts overlap the program"));
+        }
+        if self.stack_depth < 2 || self.stack_depth > 255 {
+            return Err(VmError::InvalidConfig("the stack depth must be between 2 and 255"));
+        }
+        if self.timing == Timing::Fixed && self.cpu_frequency == 0 {
+            return Err(VmError::InvalidConfig("the CPU frequency can't be 0"));
+        }
+
+        Ok(())
+    }
+
+    fn fonts_size(&self) -> usize {
+        match self.fonts {
+            Fonts::None => 0,
+            Fonts::Chip8 => specs::SPRITES_ADDR + specs::SPRITES_SIZE,
+            Fonts::SuperChip => specs::BIG_SPRITES_ADDR + specs::BIG_SPRITES_SIZE,
+        }
+    }
+}
+
+pub struct VmBuilder {
+    config: VmConfig,
+    rom: Option<Vec<u8>>,
+    clock: Option<Box<dyn Clock + Send>>,
+    display: Option<Box<dyn DisplaySink + Send>>,
+    audio: Option<Box<dyn AudioSink + Send>>,
+    observers: Vec<Box<dyn VmObserver + Send>>,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+}
+
+impl Default for VmBuilder {
+    fn default() -> VmBuilder {
+        VmBuilder::new()
+    }
+}
+
+impl VmBuilder {
+    pub fn new() -> VmBuilder {
+        VmBuilder::from_config(VmConfig::default())
+    }
+
+    pub fn from_config(config: VmConfig) -> VmBuilder {
+        VmBuilder {
+            config,
+            rom: None,
+            clock: None,
+            display: None,
+            audio: None,
+            observers: vec![],
+            unknown_opcode_policy: UnknownOpcodePolicy::Halt,
+        }
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    pub fn ram_size(mut self, size: usize) -> VmBuilder {
+        self.config.ram_size = size;
+        self
+    }
+
+    pub fn program_start(mut self, addr: usize) -> VmBuilder {
+        self.config.program_start = addr;
+        self
+    }
+
+    pub fn entry_point(mut self, addr: usize) -> VmBuilder {
+        self.config.entry_point = Some(addr);
+        self
+    }
+
+    pub fn hires_chip8(mut self, enabled: bool) -> VmBuilder {
+        self.config.hires_chip8 = enabled;
+        self
+    }
+
+    pub fn fonts(mut self, fonts: Fonts) -> VmBuilder {
+        self.config.fonts = fonts;
+        self
+    }
+
+    pub fn stack_depth(mut self, depth: usize) -> VmBuilder {
+        self.config.stack_depth = depth;
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> VmBuilder {
+        self.config.quirks = quirks;
+        self
+    }
+
+    pub fn timing(mut self, timing: Timing) -> VmBuilder {
+        self.config.timing = timing;
+        self
+    }
+
+    pub fn cpu_frequency(mut self, hz: u64) -> VmBuilder {
+        self.config.cpu_frequency = hz;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> VmBuilder {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn rewind_frames(mut self, frames: usize) -> VmBuilder {
+        self.config.rewind_frames = frames;
+        self
+    }
+
+    pub fn machine_code(mut self, enabled: bool) -> VmBuilder {
+        self.config.machine_code = enabled;
+        self
+    }
+
+    pub fn rom(mut self, rom: Vec<u8>) -> VmBuilder {
+        self.rom = Some(rom);
+        self
+    }
+
+    /// Defaults to a `RealtimeClock`.
+    pub fn clock(mut self, clock: Box<dyn Clock + Send>) -> VmBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn display(mut self, display: Box<dyn DisplaySink + Send>) -> VmBuilder {
+        self.display = Some(display);
+        self
+    }
+
+    pub fn audio(mut self, sink: Box<dyn AudioSink + Send>) -> VmBuilder {
+        self.audio = Some(sink);
+        self
+    }
+
+    pub fn observer(mut self, observer: Box<dyn VmObserver + Send>) -> VmBuilder {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn unknown_opcode_policy(mut self, policy: UnknownOpcodePolicy) -> VmBuilder {
+        self.unknown_opcode_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<VM, VmError> {
+        let config = self.config;
+        config.validate()?;
+
+        let rom = match self.rom {
+            Some(rom) => rom,
+            None => return Err(VmError::InvalidConfig("there is no ROM to run")),
+        };
+
+        let mut vm = VM::boot();
+        vm.set_ram_size(config.ram_size)
+            .set_program_start(config.program_start)
+            .set_stack_depth(config.stack_depth)?
+            .set_hires_chip8(config.hires_chip8)
+            .set_quirks(config.quirks)
+            .set_cpu_frequency(config.cpu_frequency)
+            .set_timing(config.timing)
+            .set_rewind_frames(config.rewind_frames)
+            .set_machine_code(config.machine_code)
+            .set_unknown_opcode_policy(self.unknown_opcode_policy);
+
+        match config.fonts {
+            Fonts::None => (),
+            Fonts::Chip8 => {
+                let start = specs::SPRITES_ADDR;
+                vm.ram.as_mut_slice()[start..start + specs::SPRITES_SIZE]
+                    .copy_from_slice(&specs::SPRITES);
+            }
+            Fonts::SuperChip => {
+                vm.load_sprites();
+            }
+        };
+
+        if let Some(seed) = config.seed {
+            vm.set_rng_seed(seed);
+        }
+
+        vm.load_rom(&mut &rom[..])?;
+        if let Some(entry_point) = config.entry_point {
+            vm.set_entry_point(entry_point);
+        }
+
+        if let Some(display) = self.display {
+            vm.set_display(display);
+            // The display starts with the screen of the VM
+            vm.redraw()?;
+        }
+        if let Some(sink) = self.audio {
+            vm.set_audio_sink(sink);
+        }
+        for observer in self.observers {
+            vm.add_observer(observer);
+        }
+        match self.clock {
+            Some(clock) => vm.set_clock(clock),
+            None => vm.set_clock(Box::new(RealtimeClock::new())),
+        };
+
+        Ok(vm)
+    }
+}
@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum VmError {
+    // The ROM or the save state could not be read or written
+    Io(io::Error),
+    // The ROM doesn't fit between the program start and the end of the RAM
+    RomTooLarge { size: usize, capacity: usize },
+    // CALL with every slot of the stack in use
+    StackOverflow { pc: usize, opcode: u16 },
+    // RET without any return address in the stack
+    StackUnderflow { pc: usize, opcode: u16 },
+    // The instruction tried to access memory outside of the RAM
+    InvalidAddress { pc: usize, opcode: u16, address: usize },
+    // The word at the PC is not a known instruction
+    UnknownOpcode { pc: usize, opcode: u16 },
+    // The PC points outside of the RAM
+    InvalidPc { pc: usize },
+    // The machine code routine called at the PC never returned
+    MachineCodeTimeout { pc: usize, address: usize },
+    // The display hung up the bus
+    DisplayDisconnected,
+    // The save state is corrupt or was taken from a different VM
+    InvalidSaveState(&'static str),
+    // The save state was written by an unsupported version of the format
+    SaveStateVersion { version: u8 },
+    // The VM can't be built with the given settings
+    InvalidConfig(&'static str),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::Io(ref err) => write!(f, "I/O error: {}", err),
+            VmError::RomTooLarge { size, capacity } => {
+                write!(f, "ROM too large: {} bytes, but only {} fit in memory", size, capacity)
+            }
+            VmError::StackOverflow { pc, opcode } => {
+                write!(f, "Stack overflow at {:#06X} ({:#06X})", pc, opcode)
+            }
+            VmError::StackUnderflow { pc, opcode } => {
+                write!(f, "Stack underflow at {:#06X} ({:#06X})", pc, opcode)
+            }
+            VmError::InvalidAddress { pc, opcode, address } => {
+                write!(f,
+                       "Invalid memory address {:#06X} at {:#06X} ({:#06X})",
+                       address,
+                       pc,
+                       opcode)
+            }
+            VmError::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown instruction {:#06X} at {:#06X}", opcode, pc)
+            }
+            VmError::InvalidPc { pc } => write!(f, "PC out of memory at {:#06X}", pc),
+            VmError::MachineCodeTimeout { pc, address } => {
+                write!(f,
+                       "The machine code routine at {:#06X} called from {:#06X} didn't return",
+                       address,
+                       pc)
+            }
+            VmError::DisplayDisconnected => write!(f, "The display is disconnected"),
+            VmError::InvalidSaveState(reason) => write!(f, "Invalid save state: {}", reason),
+            VmError::SaveStateVersion { version } => {
+                write!(f, "Unsupported save state version {}", version)
+            }
+            VmError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            VmError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(err: io::Error) -> VmError {
+        VmError::Io(err)
+    }
+}
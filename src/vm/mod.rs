@@ -3,39 +3,122 @@
 // TODO: Use consistent indexes with hex values.
 
 mod runtime;
-
-use std::io::Read;
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+mod error;
+mod snapshot;
+mod builder;
+mod observer;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
 use std::time::Duration;
-use std::thread;
 
-use instructions::Instruction;
+use audio::AudioSink;
+use cdp1802::Cdp1802;
+use clock::{Clock, RealtimeClock};
+use instructions::{Instruction, Opcode};
+use memory::Bus;
 use keypad::Key;
-use display::Pixel;
+use display::{DisplaySink, Frame, Rect};
+use image::Screenshot;
+use quirks::Quirks;
+use random::{RandomSource, SplitMix};
 use specs;
+use timing::{self, Timing, VIP_CYCLES_PER_FRAME, VIP_DISPLAY_CYCLES};
 use vm::runtime::Next;
 
+pub use vm::error::VmError;
+pub use vm::snapshot::Snapshot;
+pub use vm::builder::{VmBuilder, VmConfig, Fonts};
+pub use vm::observer::VmObserver;
+
 #[derive(Debug,Copy,Clone,PartialEq)]
-struct Tick;
+pub enum State {
+    Running,
+    // The program jumped to itself and can't do anything else. The
+    // timers keep running.
+    Halted,
+    // The program executed 00FD
+    Exited,
+}
+
+/// What the VM does when it fetches a word that isn't a known instruction.
+pub enum UnknownOpcodePolicy {
+    // Stop with `VmError::UnknownOpcode`
+    Halt,
+    // Skip the word and keep going
+    Skip,
+    // Call the handler with the PC and the opcode. The word is skipped
+    // unless the handler returns an error.
+    Trap(Box<dyn FnMut(usize, Opcode) -> Result<(), VmError> + Send>),
+}
 
 pub struct VM {
-    ram: [u8; specs::RAM_SIZE], // Memory
+    ram: Bus, // Memory
     registers: [u8; specs::GENERAL_REGISTERS_SIZE], // V0 - VF registers
-    stack: [u16; specs::STACK_SIZE], // Stack for return addresses of subroutines
-    keypad: [u8; specs::KEYPAD_SIZE], // Keep track of any key pressed in the keypad
-    gfx: [u8; specs::DISPLAY_PIXELS], // Graphics "card"
+    stack: Vec<u16>, // Stack for return addresses of subroutines
+    keypad: [bool; specs::KEYPAD_SIZE], // Keys held down
+    gfx: Vec<u8>, // Graphics "card"
+    flags: [u8; specs::FLAGS_SIZE], // RPL user flags
+
+    width: usize, // Current display resolution
+    height: usize,
+    planes: u8, // Bitplanes selected for drawing
+
+    audio_pattern: [u8; specs::AUDIO_PATTERN_SIZE], // XO-CHIP audio buffer
+    pitch: u8, // XO-CHIP audio playback pitch
 
     i: usize, // Store memory addresses
+    pressed_key: Option<usize>, // FX0A waits for this key to be released
+    key_wait: bool, // The observers know FX0A is waiting
 
     dt: u8, // Delay Timer register
     st: u8, // Sound Timer register
 
     pc: usize, // Program Counter
     sp: usize, // Stack Pointer
+    program_start: usize, // Where ROMs are loaded
+    entry_point: usize, // PC on reset
+    hires_chip8: bool, // 64x64 display and the 1260 jump of HiRes CHIP-8
+
+    cpu_frequency: u64, // Instructions per second
+    cpu_budget: u64, // Pending instructions, scaled by the timer frequency
+    timing: Timing, // How much each instruction costs
+    cycle_budget: i64, // VIP machine cycles left in the frame
+
+    quirks: Quirks, // Platform specific behaviours
+    vblank_wait: bool, // A draw is waiting for the next frame
+    idle: bool, // The program waits for a timer tick or a key
+    // PC, V, I and SP at the last backward jump of the frame
+    idle_probe: Option<(usize, [u8; specs::GENERAL_REGISTERS_SIZE], usize, usize)>,
+
+    rng: Box<dyn RandomSource + Send>, // Source for CXNN
+
+    decode_cache: Option<Vec<Option<Instruction>>>, // Decoded instructions by address
+    cdp1802: Option<Cdp1802>, // Runs the 0NNN machine code routines
+
+    state: State,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+
+    history: VecDeque<Snapshot>, // Snapshots of the last frames, newest last
+    history_size: usize, // How many frames can be rewound
+    rewinding: bool, // Frames go backwards while set
+
+    display: Option<Box<dyn DisplaySink + Send>>, // Receives a frame at every vblank
+    dirty: Option<Rect>, // Screen area changed since the last frame
+    audio: Option<Box<dyn AudioSink + Send>>, // Plays the sound timer
+    sounding: bool, // The audio sink is playing
+
+    clock: Option<Box<dyn Clock + Send>>, // Decides when frames run
+
+    observers: Vec<Box<dyn VmObserver + Send>>, // Notified of what the program does
+}
 
-    display_bus: Option<Sender<Vec<Pixel>>>, // Bus for the display
+// Hosts may run the VM on a worker thread, so it has to stay Send.
+fn _assert_send<T: Send>() {}
 
-    clock: Option<Receiver<Tick>>, // Clock notifications
+fn _vm_is_send() {
+    _assert_send::<VM>();
 }
 
 impl VM {
@@ -43,117 +126,685 @@ impl VM {
         info!("Booting VM");
 
         VM {
-            ram: [0; specs::RAM_SIZE],
+            ram: Bus::new(specs::RAM_SIZE),
             registers: [0; specs::GENERAL_REGISTERS_SIZE],
-            stack: [0; specs::STACK_SIZE],
-            keypad: [0; specs::KEYPAD_SIZE],
-            gfx: [0; specs::DISPLAY_PIXELS],
+            stack: vec![0; specs::STACK_SIZE],
+            keypad: [false; specs::KEYPAD_SIZE],
+            gfx: vec![0; specs::DISPLAY_PIXELS],
+            flags: [0; specs::FLAGS_SIZE],
+
+            width: specs::DISPLAY_WIDTH,
+            height: specs::DISPLAY_HEIGHT,
+            planes: 0b01,
+
+            audio_pattern: [0; specs::AUDIO_PATTERN_SIZE],
+            pitch: specs::DEFAULT_PITCH,
 
             pc: specs::PROGRAM_START,
+            program_start: specs::PROGRAM_START,
+            entry_point: specs::PROGRAM_START,
+            hires_chip8: false,
             i: 0,
+            pressed_key: None,
+            key_wait: false,
             sp: 0,
             dt: 0,
             st: 0,
 
-            display_bus: None,
+            cpu_frequency: specs::CPU_FREQUENCY,
+            cpu_budget: 0,
+            timing: Timing::default(),
+            cycle_budget: 0,
+
+            quirks: Quirks::default(),
+            vblank_wait: false,
+            idle: false,
+            idle_probe: None,
+
+            rng: Box::new(SplitMix::from_entropy()),
+
+            decode_cache: Some(vec![]),
+            cdp1802: None,
+
+            state: State::Running,
+            unknown_opcode_policy: UnknownOpcodePolicy::Halt,
+
+            history: VecDeque::new(),
+            history_size: 0,
+            rewinding: false,
+
+            display: None,
+            dirty: None,
+            audio: None,
+            sounding: false,
             clock: None,
+
+            observers: vec![],
         }
     }
 
-    pub fn load_sprites<'a>(&'a mut self) -> &'a mut VM {
+    pub fn load_sprites(&mut self) -> &mut VM {
         info!("Loading SPRITES into memory");
 
         let range = specs::SPRITES_ADDR..(specs::SPRITES_ADDR + specs::SPRITES_SIZE);
         for addr in range {
-            let index = (addr - specs::SPRITES_ADDR) as usize;
+            let index = addr - specs::SPRITES_ADDR;
             self.ram[addr] = specs::SPRITES[index];
         }
 
+        let range = specs::BIG_SPRITES_ADDR..(specs::BIG_SPRITES_ADDR + specs::BIG_SPRITES_SIZE);
+        for addr in range {
+            let index = addr - specs::BIG_SPRITES_ADDR;
+            self.ram[addr] = specs::BIG_SPRITES[index];
+        }
+        self.clear_decoded();
+
+        self
+    }
+
+    /// Resizes the memory, e.g. to `specs::XO_RAM_SIZE` for XO-CHIP ROMs.
+    pub fn set_ram_size(&mut self, size: usize) -> &mut VM {
+        self.ram.resize(size);
+        self.clear_decoded();
+
         self
     }
 
-    pub fn load_rom<'a>(&'a mut self, reader: &mut Read) -> &'a mut VM {
+    pub fn load_rom(&mut self, reader: &mut dyn Read) -> Result<&mut VM, VmError> {
         info!("Loading ROM into memory");
 
         let mut rom = Vec::new();
-        if let Err(_) = reader.read_to_end(&mut rom) {
-            panic!("Error reading ROM");
+        reader.read_to_end(&mut rom)?;
+
+        let capacity = self.ram.len().saturating_sub(self.program_start);
+        if rom.len() > capacity {
+            return Err(VmError::RomTooLarge { size: rom.len(), capacity });
         }
 
-        let mut addr = specs::PROGRAM_START;
-        for byte in &rom {
+        for (addr, byte) in (self.program_start..).zip(rom.iter()) {
             self.ram[addr] = *byte;
-            addr += 1;
+        }
+        self.clear_decoded();
+
+        Ok(self)
+    }
+
+    /// Takes a copy of the running state of the VM.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::take(self)
+    }
+
+    /// Puts the VM back in the state of the snapshot and redraws the display.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmError> {
+        snapshot.restore(self)?;
+        self.clear_decoded();
+        self.idle = false;
+        self.idle_probe = None;
+
+        self.redraw()
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> Result<(), VmError> {
+        info!("Saving VM state");
+
+        self.snapshot().write_to(writer)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), VmError> {
+        info!("Loading VM state");
+
+        let snapshot = Snapshot::read_from(reader)?;
+        self.restore(&snapshot)?;
+        self.history.clear();
+
+        Ok(())
+    }
+
+    pub fn save_screenshot(&self,
+                           writer: &mut dyn Write,
+                           screenshot: &Screenshot)
+                           -> Result<(), VmError> {
+        info!("Saving screenshot");
+
+        screenshot.write(&self.frame(), writer)?;
+        Ok(())
+    }
+
+    /// Keeps a snapshot of each of the last `frames` frames so they can be
+    /// rewound, e.g. `specs::REWIND_FRAMES`. Zero disables rewinding.
+    pub fn set_rewind_frames(&mut self, frames: usize) -> &mut VM {
+        self.history_size = frames;
+        while self.history.len() > frames {
+            self.history.pop_front();
         }
 
         self
     }
 
-    pub fn set_display_bus<'a>(&'a mut self, bus: Sender<Vec<Pixel>>) -> &'a mut VM {
-        self.display_bus = Some(bus);
+    /// Goes back to the state the VM had `frames` frames ago, or to the oldest
+    /// frame kept. Returns how many frames were actually rewound.
+    pub fn rewind(&mut self, frames: usize) -> Result<usize, VmError> {
+        // The newest snapshot is the current state, so it always takes one
+        // more snapshot than frames rewound.
+        let frames = frames.min(self.history.len().saturating_sub(1));
+        if frames == 0 {
+            return Ok(0);
+        }
+
+        for _ in 0..frames {
+            self.history.pop_back();
+        }
+        if let Some(snapshot) = self.history.back().cloned() {
+            // The keys held now are the player's, not the ones of the past
+            let keypad = self.keypad;
+            self.restore(&snapshot)?;
+            self.keypad = keypad;
+            self.pressed_key = None;
+        }
+
+        Ok(frames)
+    }
+
+    /// While set, each frame of `cycle` rewinds one frame instead of running.
+    pub fn set_rewinding(&mut self, rewinding: bool) {
+        self.rewinding = rewinding;
+    }
+
+    /// Moves the address ROMs are loaded at, and the PC with it.
+    /// Also moves the entry point to `addr`.
+    pub fn set_program_start(&mut self, addr: usize) -> &mut VM {
+        self.program_start = addr;
+        self.set_entry_point(addr)
+    }
+
+    pub fn set_entry_point(&mut self, addr: usize) -> &mut VM {
+        self.entry_point = addr;
+        self.pc = addr;
 
         self
     }
 
-    pub fn init_clock<'a>(&'a mut self) -> &'a mut VM {
-        let (ticker, clock) = channel();
+    /// Puts the PC back on the entry point, like a reset of the machine.
+    /// Nothing else changes.
+    pub fn reset_pc(&mut self) {
+        self.pc = self.entry_point;
+    }
 
-        let _ = thread::spawn(move || {
-            'clock: loop {
-                thread::sleep(Duration::from_millis(specs::CLOCK));
-                if ticker.send(Tick).is_err() {
-                    break 'clock;
-                };
-            }
-        });
+    /// HiRes CHIP-8 runs on a 64x64 display. Its ROMs start with 1260,
+    /// which jumps over the interpreter patch to 0x2C0.
+    pub fn set_hires_chip8(&mut self, enabled: bool) -> &mut VM {
+        self.hires_chip8 = enabled;
+        let (width, height) = if enabled {
+            (specs::HIRES_CHIP8_DISPLAY_WIDTH, specs::HIRES_CHIP8_DISPLAY_HEIGHT)
+        } else {
+            (specs::DISPLAY_WIDTH, specs::DISPLAY_HEIGHT)
+        };
+        self.width = width;
+        self.height = height;
+        self.gfx = vec![0; width * height];
+        self.mark_screen_dirty();
+
+        self
+    }
+
+    /// Sets how many return addresses fit in the stack, between 2 and 255
+    /// as snapshots save the depth in a single byte.
+    pub fn set_stack_depth(&mut self, depth: usize) -> Result<&mut VM, VmError> {
+        if !(2..=255).contains(&depth) {
+            return Err(VmError::InvalidConfig("the stack depth must be between 2 and 255"));
+        }
+        self.stack = vec![0; depth];
+        self.sp = 0;
+
+        Ok(self)
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink + Send>) -> &mut VM {
+        self.audio = Some(sink);
+
+        self
+    }
+
+    pub fn set_display(&mut self, display: Box<dyn DisplaySink + Send>) -> &mut VM {
+        self.display = Some(display);
+
+        self
+    }
+
+    /// Sets how many instructions per second the CPU executes. The delay and
+    /// sound timers always count down at `specs::TIMER_FREQUENCY`.
+    pub fn set_cpu_frequency(&mut self, hz: u64) -> &mut VM {
+        self.cpu_frequency = hz;
+        self.cpu_budget = 0;
+
+        self
+    }
+
+    /// Same as `set_cpu_frequency`, but expressed as instructions per frame.
+    pub fn set_instructions_per_frame(&mut self, instructions: u64) -> &mut VM {
+        self.set_cpu_frequency(instructions * specs::TIMER_FREQUENCY)
+    }
+
+    /// Chooses between a fixed CPU rate and the instruction costs
+    /// of the COSMAC VIP.
+    pub fn set_timing(&mut self, timing: Timing) -> &mut VM {
+        self.timing = timing;
+        self.cpu_budget = 0;
+        self.cycle_budget = 0;
+
+        self
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) -> &mut VM {
+        self.quirks = quirks;
+
+        self
+    }
 
+    /// Seeds the random number generator, so runs can be repeated.
+    pub fn set_rng_seed(&mut self, seed: u64) -> &mut VM {
+        self.rng = Box::new(SplitMix::new(seed));
+
+        self
+    }
+
+    pub fn set_random_source(&mut self, source: Box<dyn RandomSource + Send>) -> &mut VM {
+        self.rng = source;
+
+        self
+    }
+
+    /// Keeps the decoded instructions by address, so they are decoded only
+    /// once. Writes done by the program invalidate them. Enabled by default.
+    pub fn set_decode_cache(&mut self, enabled: bool) -> &mut VM {
+        self.decode_cache = if enabled { Some(vec![]) } else { None };
+
+        self
+    }
+
+    /// Runs 0NNN as calls to CDP1802 machine code, like the COSMAC VIP.
+    /// Without it they are unknown instructions.
+    pub fn set_machine_code(&mut self, enabled: bool) -> &mut VM {
+        self.cdp1802 = if enabled { Some(Cdp1802::new()) } else { None };
+        self
+    }
+
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) -> &mut VM {
+        self.unknown_opcode_policy = policy;
+
+        self
+    }
+
+    /// Adds an observer of the VM, see `VmObserver`. Observers are notified
+    /// in the order they were added.
+    pub fn add_observer(&mut self, observer: Box<dyn VmObserver + Send>) -> &mut VM {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn clear_observers(&mut self) -> &mut VM {
+        self.observers.clear();
+        self
+    }
+
+    /// Sets the clock that decides how many frames each `cycle` runs.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock + Send>) -> &mut VM {
         self.clock = Some(clock);
 
         self
     }
 
-    pub fn cycle(&mut self) {
-        if self.tick() {
-            let mut bytes = self.ram[self.pc] as u16;
-            bytes = bytes << 8;
-            bytes = bytes | self.ram[self.pc + 1] as u16;
+    /// Follows the wall clock, see `clock::RealtimeClock`.
+    pub fn init_clock(&mut self) -> &mut VM {
+        self.set_clock(Box::new(RealtimeClock::new()))
+    }
 
-            match Instruction::decode(bytes) {
-                Some(ins) => {
-                    debug!("Decoded instruction {:?}", ins);
-                    self.exec(ins);
-                }
-                None => debug!("Unknown instruction {:?}", bytes),
+    pub fn cycle(&mut self) -> Result<(), VmError> {
+        // If the host fell behind the clock hands out every pending
+        // frame, so the timers keep the right pace.
+        let frames = match self.clock {
+            Some(ref mut clock) => clock.pending_frames(),
+            None => 0,
+        };
+
+        for _ in 0..frames {
+            if self.rewinding {
+                self.rewind(1)?;
+            } else {
+                self.step_frame()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Executes exactly one instruction. The timers are not updated, but
+    /// the display gets the changes right away.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        if self.state != State::Running {
+            return Ok(());
+        }
+
+        self.fetch_and_exec()?;
+        self.present()
+    }
+
+    /// Runs one 60 Hz frame: the instructions of the frame at the current
+    /// CPU rate, then the timers.
+    pub fn step_frame(&mut self) -> Result<(), VmError> {
+        self.vblank_wait = false;
+        self.idle = false;
+        self.idle_probe = None;
+        match self.timing {
+            // The budget is kept scaled by the timer frequency so
+            // rates that are not a multiple of 60 Hz don't drift.
+            Timing::Fixed => self.cpu_budget += self.cpu_frequency,
+            // Whatever the last instruction overran is paid by this frame.
+            Timing::Vip => self.cycle_budget += VIP_CYCLES_PER_FRAME - VIP_DISPLAY_CYCLES,
+        };
+
+        while self.frame_budget_left() {
+            match self.state {
+                State::Running => (),
+                State::Halted => break,
+                State::Exited => return self.vblank(),
             };
 
-            // Decrement the timers
-            if self.dt > 0 {
-                self.dt -= 1;
+            if self.timing == Timing::Fixed {
+                self.cpu_budget -= specs::TIMER_FREQUENCY;
             }
+            self.fetch_and_exec()?;
 
-            if self.st > 0 {
-                println!("BEEP!"); // TODO: Sound system.
-                self.st -= 1;
+            if self.vblank_wait || self.idle {
+                // The rest of the frame is spent waiting for the display,
+                // or for the timers.
+                self.cpu_budget %= specs::TIMER_FREQUENCY;
+                self.cycle_budget = cmp::min(self.cycle_budget, 0);
             }
+        }
+
+        self.vblank()?;
+        self.decrement_timers();
+        self.record_frame();
+
+        Ok(())
+    }
+
+    /// Sends the whole screen to the display now, e.g. after the
+    /// VM state has been replaced.
+    pub fn redraw(&mut self) -> Result<(), VmError> {
+        self.mark_screen_dirty();
+        self.present()
+    }
+
+    // Publishes the frame at vblank, or tells the display nothing changed.
+    fn vblank(&mut self) -> Result<(), VmError> {
+        if self.dirty.is_some() {
+            return self.present();
+        }
+
+        if let Some(ref mut display) = self.display {
+            display.repeat();
+        }
+
+        Ok(())
+    }
+
+    // Publishes the screen, if anything changed.
+    fn present(&mut self) -> Result<(), VmError> {
+        let frame = match self.dirty.take() {
+            Some(dirty) if self.display.is_some() => Frame { dirty, ..self.frame() },
+            _ => return Ok(()),
         };
+
+        if let Some(ref mut display) = self.display {
+            display.present(&frame).map_err(|_| VmError::DisplayDisconnected)?;
+        }
+
+        Ok(())
+    }
+
+    fn mark_dirty(&mut self, area: Rect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(&area),
+            None => area,
+        });
+    }
+
+    // After a change of resolution the old area doesn't matter.
+    fn mark_screen_dirty(&mut self) {
+        self.dirty = Some(Rect::new(0, 0, self.width, self.height));
     }
 
-    fn tick(&mut self) -> bool {
+    /// How long the host can sleep before the next frame, when the clock
+    /// follows the wall clock.
+    pub fn next_frame_in(&self) -> Option<Duration> {
         match self.clock {
-            None => false,
-            Some(ref clk) => {
-                match clk.try_recv() {
-                    Err(TryRecvError::Disconnected) => panic!("The clock died!"),
-                    Ok(Tick) => true,
-                    _ => false,
+            Some(ref clock) => clock.next_frame_in(),
+            None => None,
+        }
+    }
+
+    // A loop that comes back to the same state without any side effect
+    // can only be left by a timer tick or a key event.
+    fn check_idle_loop(&mut self) {
+        let probe = (self.pc, self.registers, self.i, self.sp);
+        if self.idle_probe == Some(probe) {
+            self.idle = true;
+        } else {
+            self.idle_probe = Some(probe);
+        }
+    }
+
+    fn frame_budget_left(&self) -> bool {
+        match self.timing {
+            Timing::Fixed => self.cpu_budget >= specs::TIMER_FREQUENCY,
+            Timing::Vip => self.cycle_budget > 0,
+        }
+    }
+
+    /// Runs `frames` frames as fast as possible, without waiting for a clock.
+    /// It stops early if the program exits or halts.
+    pub fn run_for(&mut self, frames: usize) -> Result<(), VmError> {
+        for _ in 0..frames {
+            if self.state != State::Running {
+                break;
+            }
+
+            self.step_frame()?;
+        }
+
+        Ok(())
+    }
+
+    fn record_frame(&mut self) {
+        if self.history_size == 0 {
+            return;
+        }
+
+        if self.history.len() == self.history_size {
+            self.history.pop_front();
+        }
+        let snapshot = match self.history.back() {
+            Some(previous) => Snapshot::take_after(self, previous),
+            None => self.snapshot(),
+        };
+        self.history.push_back(snapshot);
+    }
+
+    fn fetch_and_exec(&mut self) -> Result<(), VmError> {
+        if self.pc + 1 >= self.ram.len() {
+            return Err(VmError::InvalidPc { pc: self.pc });
+        }
+
+        let pc = self.pc;
+        if let Some(ins) = self.cached_instruction(pc) {
+            return self.exec_fetched(ins);
+        }
+
+        let mut bytes = self.ram.fetch(pc) as u16;
+        bytes <<= 8;
+        bytes |= self.ram.fetch(pc + 1) as u16;
+
+        match Instruction::decode(bytes) {
+            Some(ins) => {
+                debug!("Decoded instruction {:?}", ins);
+                self.cache_instruction(pc, ins);
+                self.exec_fetched(ins)
+            }
+            None => self.unknown_opcode(bytes),
+        }
+    }
+
+    fn exec_fetched(&mut self, ins: Instruction) -> Result<(), VmError> {
+        if self.timing == Timing::Vip {
+            self.cycle_budget -= timing::vip_cycles(&ins, &self.registers);
+        }
+
+        self.exec(ins)
+    }
+
+    // The cache is skipped while the bus has any observer, so every
+    // fetch still reaches them.
+    fn cached_instruction(&self, pc: usize) -> Option<Instruction> {
+        match self.decode_cache {
+            Some(ref cache) if self.ram.is_plain() => cache.get(pc).cloned().unwrap_or(None),
+            _ => None,
+        }
+    }
+
+    fn cache_instruction(&mut self, pc: usize, ins: Instruction) {
+        if let Some(ref mut cache) = self.decode_cache {
+            if cache.len() != self.ram.len() {
+                *cache = vec![None; self.ram.len()];
+            }
+            cache[pc] = Some(ins);
+        }
+    }
+
+    // An instruction at `addr - 1` also uses the byte at `addr`.
+    fn invalidate_decoded(&mut self, addr: usize) {
+        if let Some(ref mut cache) = self.decode_cache {
+            if let Some(ins) = cache.get_mut(addr) {
+                *ins = None;
+            }
+            if addr > 0 {
+                if let Some(ins) = cache.get_mut(addr - 1) {
+                    *ins = None;
                 }
             }
         }
     }
 
-    pub fn set_key(&mut self, key: Key) {
-        debug!("Key {:?} pressed", key);
-        self.keypad[key.as_usize()] += 1;
+    // Drops every decoded instruction, after the memory changed
+    // without going through the runtime.
+    fn clear_decoded(&mut self) {
+        if let Some(ref mut cache) = self.decode_cache {
+            cache.clear();
+        }
+    }
+
+    fn unknown_opcode(&mut self, bytes: u16) -> Result<(), VmError> {
+        let pc = self.pc;
+        warn!("Unknown instruction {:#06X} at {:#06X}", bytes, pc);
+
+        match self.unknown_opcode_policy {
+            UnknownOpcodePolicy::Halt => {
+                return Err(VmError::UnknownOpcode { pc, opcode: bytes });
+            }
+            UnknownOpcodePolicy::Skip => (),
+            UnknownOpcodePolicy::Trap(ref mut handler) => handler(pc, Opcode::new(bytes))?,
+        };
+
+        self.advance();
+
+        Ok(())
+    }
+
+    fn decrement_timers(&mut self) {
+        if self.dt > 0 {
+            self.dt -= 1;
+        }
+
+        if self.st > 0 {
+            self.sound(true);
+            self.st -= 1;
+        }
+
+        if self.st == 0 {
+            self.sound(false);
+        }
+    }
+
+    fn sound(&mut self, on: bool) {
+        if on == self.sounding {
+            return;
+        }
+        self.sounding = on;
+        self.notify(|o| if on { o.on_sound_start() } else { o.on_sound_stop() });
+
+        let rate = self.playback_rate();
+        match self.audio {
+            Some(ref mut sink) if on => sink.start(&self.audio_pattern, rate),
+            Some(ref mut sink) => sink.stop(),
+            None if on => debug!("Beep without an audio sink"),
+            None => (),
+        }
+    }
+
+    /// The memory bus, e.g. to add watchpoints or count accesses.
+    pub fn memory(&self) -> &Bus {
+        &self.ram
+    }
+
+    /// Writing through it drops every decoded instruction, as the
+    /// VM can't tell which bytes changed.
+    pub fn memory_mut(&mut self) -> &mut Bus {
+        self.clear_decoded();
+        &mut self.ram
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Returns the current display resolution as `(width, height)`.
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// The whole screen as it is now.
+    pub fn frame(&self) -> Frame {
+        Frame {
+            width: self.width,
+            height: self.height,
+            pixels: self.gfx.clone(),
+            dirty: Rect::new(0, 0, self.width, self.height),
+        }
+    }
+
+    /// Returns the XO-CHIP audio pattern: 128 1-bit samples.
+    pub fn audio_pattern(&self) -> &[u8] {
+        &self.audio_pattern
+    }
+
+    /// Returns the rate, in Hz, at which the audio pattern is played.
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    pub fn key_down(&mut self, key: Key) {
+        debug!("Key {:?} down", key);
+        self.keypad[key.as_usize()] = true;
+    }
+
+    pub fn key_up(&mut self, key: Key) {
+        debug!("Key {:?} up", key);
+        self.keypad[key.as_usize()] = false;
+    }
+
+    pub fn is_key_down(&self, key: Key) -> bool {
+        self.keypad[key.as_usize()]
     }
 
     pub fn advance(&mut self) {
@@ -168,10 +819,33 @@ impl VM {
         }
     }
 
-    pub fn exec(&mut self, instruction: Instruction) {
+    fn notify<F>(&mut self, mut event: F)
+        where F: FnMut(&mut dyn VmObserver)
+    {
+        for observer in &mut self.observers {
+            event(observer.as_mut());
+        }
+    }
+
+    pub fn exec(&mut self, instruction: Instruction) -> Result<(), VmError> {
+        let pc = self.pc;
+        self.notify(|o| o.on_instruction(pc, &instruction));
+
+        if has_side_effects(&instruction) {
+            self.idle_probe = None;
+        }
+
         let next = match instruction {
             Instruction::Clear => runtime::clear(self),
             Instruction::Return => runtime::ret(self),
+            Instruction::ScrollDown(opcode) => runtime::scroll_down(self, opcode),
+            Instruction::ScrollUp(opcode) => runtime::scroll_up(self, opcode),
+            Instruction::ScrollRight => runtime::scroll_right(self),
+            Instruction::ScrollLeft => runtime::scroll_left(self),
+            Instruction::Exit => runtime::exit(self),
+            Instruction::LowRes => runtime::low_res(self),
+            Instruction::HighRes => runtime::high_res(self),
+            Instruction::MachineCall(opcode) => runtime::machine_call(self, opcode),
             Instruction::Jump(opcode) => runtime::jump(self, opcode),
             Instruction::Call(opcode) => runtime::call(self, opcode),
 
@@ -185,6 +859,10 @@ impl VM {
 
             Instruction::SkipOnNotEqual(opcode) => runtime::skip_on_not_equal(self, opcode),
 
+            Instruction::StoreRange(opcode) => runtime::store_range(self, opcode),
+
+            Instruction::ReadRange(opcode) => runtime::read_range(self, opcode),
+
             Instruction::SetByte(opcode) => runtime::set_byte(self, opcode),
 
             Instruction::AddByte(opcode) => runtime::add_byte(self, opcode),
@@ -209,6 +887,8 @@ impl VM {
 
             Instruction::SetI(opcode) => runtime::set_i(self, opcode),
 
+            Instruction::LongSetI => runtime::long_set_i(self),
+
             Instruction::JumpPlus(opcode) => runtime::jump_plus(self, opcode),
 
             Instruction::RandomMask(opcode) => runtime::random_mask(self, opcode),
@@ -233,19 +913,59 @@ impl VM {
 
             Instruction::SetSprite(opcode) => runtime::set_sprite(self, opcode),
 
+            Instruction::SetBigSprite(opcode) => runtime::set_big_sprite(self, opcode),
+
+            Instruction::SelectPlane(opcode) => runtime::select_plane(self, opcode),
+
+            Instruction::LoadAudio => runtime::load_audio(self),
+
+            Instruction::SetPitch(opcode) => runtime::set_pitch(self, opcode),
+
             Instruction::Bcd(opcode) => runtime::bcd(self, opcode),
 
             Instruction::Store(opcode) => runtime::store(self, opcode),
 
             Instruction::Read(opcode) => runtime::read(self, opcode),
+
+            Instruction::StoreFlags(opcode) => runtime::store_flags(self, opcode),
+
+            Instruction::ReadFlags(opcode) => runtime::read_flags(self, opcode),
         };
 
-        match next {
+        match next? {
             Next::Advance(steps) => self.advance_by(steps),
             Next::Noop => (),
         };
+
+        Ok(())
     }
 }
 
+// Instructions that change something the idle loop detection doesn't
+// compare: memory, display, timers, sound or the random generator.
+fn has_side_effects(instruction: &Instruction) -> bool {
+    matches!(*instruction,
+        Instruction::Clear |
+        Instruction::ScrollDown(_) |
+        Instruction::ScrollUp(_) |
+        Instruction::ScrollRight |
+        Instruction::ScrollLeft |
+        Instruction::Exit |
+        Instruction::LowRes |
+        Instruction::HighRes |
+        Instruction::MachineCall(_) |
+        Instruction::StoreRange(_) |
+        Instruction::RandomMask(_) |
+        Instruction::Draw(_) |
+        Instruction::SetDelayTimer(_) |
+        Instruction::SetSoundTimer(_) |
+        Instruction::SelectPlane(_) |
+        Instruction::LoadAudio |
+        Instruction::SetPitch(_) |
+        Instruction::Bcd(_) |
+        Instruction::Store(_) |
+        Instruction::StoreFlags(_))
+}
+
 #[cfg(test)]
 mod tests;
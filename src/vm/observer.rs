@@ -0,0 +1,32 @@
+// Observers see what the VM does while it runs: debuggers, tracers,
+// profilers... Every callback does nothing by default, so an observer only
+// implements the events it cares about.
+
+use instructions::Instruction;
+
+pub trait VmObserver {
+    /// An instruction at `pc` is about to run.
+    fn on_instruction(&mut self, _pc: usize, _instruction: &Instruction) {}
+
+    /// A sprite was drawn at `(x, y)`, with `collision` if it erased any pixel.
+    fn on_draw(&mut self, _x: usize, _y: usize, _rows: usize, _collision: bool) {}
+
+    fn on_clear(&mut self) {}
+
+    fn on_sound_start(&mut self) {}
+
+    fn on_sound_stop(&mut self) {}
+
+    /// FX0A started waiting for a key into register `x`. It's called once
+    /// per wait, not on every step until a key is pressed.
+    fn on_key_wait(&mut self, _x: usize) {}
+
+    /// The subroutine at `address` was called from `pc`.
+    fn on_call(&mut self, _pc: usize, _address: usize) {}
+
+    /// The subroutine at `pc` returned to `address`.
+    fn on_return(&mut self, _pc: usize, _address: usize) {}
+
+    /// The program wrote `value` at `address`.
+    fn on_memory_write(&mut self, _address: usize, _value: u8) {}
+}
@@ -1,149 +1,402 @@
-use rand::{thread_rng, Rng};
+use std::cmp;
 
 use instructions::Opcode;
-use vm::VM;
-use display::Pixel;
+use vm::{VM, State};
+use vm::error::VmError;
+use display::Rect;
+use memory::{Bus, Memory};
+use quirks::MemoryIncrement;
 use specs;
+use timing::Timing;
+
+// Pixels moved by 00FB and 00FC
+const SCROLL_PIXELS: isize = 4;
+
+// Where the VIP interpreter keeps its state, at the top of a 4KB machine.
+// Machine code routines expect to find it there.
+const VIP_STACK: u16 = 0x0ECF;
+const VIP_VARIABLES: usize = 0x0EF0;
+const VIP_DISPLAY: usize = 0x0F00;
+// 1802 instructions a routine can run before it's considered stuck
+const MAX_MACHINE_STEPS: usize = 1_000_000;
 
 pub enum Next {
     Advance(u16),
     Noop,
 }
 
-pub fn clear(vm: &mut VM) -> Next {
-    let mut pixels: Vec<Pixel> = vec![];
-    let width = specs::DISPLAY_WIDTH as i32;
-    let mut x: i32 = 0;
-    let mut y: i32 = 0;
-
+pub fn clear(vm: &mut VM) -> Result<Next, VmError> {
+    let planes = vm.planes;
     for pixel in vm.gfx.iter_mut() {
-        *pixel = 0;
+        *pixel &= !planes;
+    }
 
-        pixels.push(Pixel::new(x, y, 0));
+    vm.mark_screen_dirty();
+    vm.notify(|o| o.on_clear());
 
-        x += 1;
-        // This is the end of the line,
-        // time to start a new one.
-        if x == width {
-            x = 0;
-            y += 1;
-        }
+    Ok(Next::Advance(1))
+}
+
+pub fn ret(vm: &mut VM) -> Result<Next, VmError> {
+    if vm.sp == 0 {
+        return Err(VmError::StackUnderflow { pc: vm.pc, opcode: 0x00EE });
     }
 
-    if let Some(ref bus) = vm.display_bus {
-        bus.send(pixels).unwrap();
+    let pc = vm.pc;
+    vm.sp -= 1;
+    vm.pc = vm.stack[vm.sp] as usize;
+
+    // It returns to the instruction after the call.
+    let address = vm.pc + 2;
+    vm.notify(|o| o.on_return(pc, address));
+
+    Ok(Next::Advance(1))
+}
+
+pub fn scroll_down(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    scroll(vm, 0, opcode.nibble as isize);
+
+    Ok(Next::Advance(1))
+}
+
+pub fn scroll_up(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    scroll(vm, 0, -(opcode.nibble as isize));
+
+    Ok(Next::Advance(1))
+}
+
+pub fn scroll_right(vm: &mut VM) -> Result<Next, VmError> {
+    scroll(vm, SCROLL_PIXELS, 0);
+
+    Ok(Next::Advance(1))
+}
+
+pub fn scroll_left(vm: &mut VM) -> Result<Next, VmError> {
+    scroll(vm, -SCROLL_PIXELS, 0);
+
+    Ok(Next::Advance(1))
+}
+
+pub fn exit(vm: &mut VM) -> Result<Next, VmError> {
+    info!("Program exited");
+    vm.state = State::Exited;
+
+    Ok(Next::Noop)
+}
+
+pub fn low_res(vm: &mut VM) -> Result<Next, VmError> {
+    set_resolution(vm, specs::DISPLAY_WIDTH, specs::DISPLAY_HEIGHT);
+
+    Ok(Next::Advance(1))
+}
+
+pub fn high_res(vm: &mut VM) -> Result<Next, VmError> {
+    set_resolution(vm, specs::HIRES_DISPLAY_WIDTH, specs::HIRES_DISPLAY_HEIGHT);
+
+    Ok(Next::Advance(1))
+}
+
+// Runs the routine like the VIP interpreter: R3 is the PC, R2 the stack,
+// R5 the CHIP-8 PC, R6 and R7 point to VX and VY, R8 holds the timers,
+// RA is I and RB the display page. The routine returns with D4 (SEP R4).
+pub fn machine_call(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let mut cpu = match vm.cdp1802 {
+        Some(cpu) => cpu,
+        None => {
+            vm.unknown_opcode(opcode.bytes)?;
+            return Ok(Next::Noop);
+        }
     };
 
-    Next::Advance(1)
+    // Without room for the interpreter state only the registers are set
+    let vip_layout = vm.ram.len() >= specs::RAM_SIZE;
+    let display_layout = vip_layout && vm.width == specs::DISPLAY_WIDTH &&
+                         vm.height == specs::DISPLAY_HEIGHT;
+    // On the VIP they always live in RAM, so only the bytes that changed
+    // since the last call are written
+    if vip_layout {
+        for v in 0..16 {
+            let value = vm.registers[v];
+            if vm.ram[VIP_VARIABLES + v] != value {
+                write(vm, VIP_VARIABLES + v, value);
+            }
+        }
+    }
+    if display_layout {
+        for idx in 0..vm.gfx.len() / 8 {
+            let byte = pack_pixels(&vm.gfx[idx * 8..idx * 8 + 8]);
+            if vm.ram[VIP_DISPLAY + idx] != byte {
+                write(vm, VIP_DISPLAY + idx, byte);
+            }
+        }
+    }
+
+    cpu.r[2] = VIP_STACK;
+    cpu.x = 2;
+    cpu.r[3] = opcode.address;
+    cpu.p = 3;
+    cpu.r[5] = (vm.pc + 2) as u16;
+    cpu.r[6] = (VIP_VARIABLES + opcode.x as usize) as u16;
+    cpu.r[7] = (VIP_VARIABLES + opcode.y as usize) as u16;
+    cpu.r[8] = (vm.dt as u16) << 8 | vm.st as u16;
+    cpu.r[0xA] = vm.i as u16;
+    cpu.r[0xB] = (VIP_DISPLAY as u16) & 0xFF00;
+
+    let mut steps = 0;
+    let mut cycles = 0;
+    let mut timed_out = false;
+    let mut memory = WriteLog { bus: &mut vm.ram, writes: vec![] };
+    while cpu.p != 4 {
+        if steps == MAX_MACHINE_STEPS {
+            timed_out = true;
+            break;
+        }
+        cycles += cpu.step(&mut memory);
+        // There are no interrupts to wake it up
+        cpu.idle = false;
+        steps += 1;
+    }
+    for (addr, value) in memory.writes {
+        vm.notify(|o| o.on_memory_write(addr, value));
+    }
+    vm.cdp1802 = Some(cpu);
+    // The routine may have written anywhere
+    vm.clear_decoded();
+
+    // What the routine did stays, so the host can look at it
+    if timed_out {
+        return Err(VmError::MachineCodeTimeout { pc: vm.pc, address: opcode.address as usize });
+    }
+
+    if vip_layout {
+        vm.registers.copy_from_slice(&vm.ram[VIP_VARIABLES..VIP_VARIABLES + 16]);
+    }
+    vm.i = cpu.r[0xA] as usize;
+    vm.dt = (cpu.r[8] >> 8) as u8;
+    vm.st = cpu.r[8] as u8;
+    vm.pc = cpu.r[5] as usize;
+    if vm.timing == Timing::Vip {
+        vm.cycle_budget -= cycles as i64;
+    }
+
+    if display_layout {
+        let mut changed = false;
+        for idx in 0..vm.gfx.len() / 8 {
+            let byte = vm.ram[VIP_DISPLAY + idx];
+            if byte == pack_pixels(&vm.gfx[idx * 8..idx * 8 + 8]) {
+                continue;
+            }
+            changed = true;
+            for (bit, pixel) in vm.gfx[idx * 8..idx * 8 + 8].iter_mut().enumerate() {
+                let on = byte & (0x80 >> bit) != 0;
+                *pixel = (*pixel & !0b01) | on as u8;
+            }
+        }
+        if changed {
+            vm.mark_screen_dirty();
+        }
+    }
+
+    Ok(Next::Noop)
 }
 
-pub fn ret(vm: &mut VM) -> Next {
-    vm.pc = vm.stack[vm.sp] as usize;
-    vm.sp -= 1;
+// The bus seen by machine code routines, which keeps their writes to tell
+// the observers once the routine returns.
+struct WriteLog<'a> {
+    bus: &'a mut Bus,
+    writes: Vec<(usize, u8)>,
+}
+
+impl<'a> Memory for WriteLog<'a> {
+    fn len(&self) -> usize {
+        self.bus.len()
+    }
 
-    Next::Advance(1)
+    fn read(&mut self, addr: usize) -> u8 {
+        self.bus.read(addr)
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        self.bus.write(addr, value);
+        self.writes.push((addr, value));
+    }
 }
 
-pub fn jump(vm: &mut VM, opcode: Opcode) -> Next {
-    vm.pc = opcode.address as usize;
+// The VIP display uses a bit per pixel, eight pixels per byte.
+fn pack_pixels(pixels: &[u8]) -> u8 {
+    pixels.iter().fold(0, |byte, &pixel| byte << 1 | (pixel & 0b01))
+}
+
+pub fn jump(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let mut address = opcode.address as usize;
+    if vm.hires_chip8 && vm.pc == vm.program_start && address == specs::HIRES_CHIP8_JUMP {
+        address = specs::HIRES_CHIP8_START;
+    }
+    if address == vm.pc {
+        info!("Program halted at {:#06X}", vm.pc);
+        vm.state = State::Halted;
+
+        return Ok(Next::Noop);
+    }
+
+    let backwards = address < vm.pc;
+    vm.pc = address;
+    if backwards {
+        vm.check_idle_loop();
+    }
 
-    Next::Noop
+    Ok(Next::Noop)
 }
 
-pub fn call(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn call(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    if vm.sp >= vm.stack.len() {
+        return Err(VmError::StackOverflow { pc: vm.pc, opcode: opcode.bytes });
+    }
+
+    let pc = vm.pc;
+    vm.stack[vm.sp] = pc as u16;
     vm.sp += 1;
-    vm.stack[vm.sp] = vm.pc as u16;
     vm.pc = opcode.address as usize;
 
-    Next::Noop
+    let address = vm.pc;
+    vm.notify(|o| o.on_call(pc, address));
+
+    Ok(Next::Noop)
 }
 
-pub fn skip_on_equal_byte(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn skip_on_equal_byte(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize];
     if vx == opcode.data {
-        Next::Advance(2)
+        Ok(skip(vm))
     } else {
-        Next::Advance(1)
+        Ok(Next::Advance(1))
     }
 }
 
-pub fn skip_on_not_equal_byte(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn skip_on_not_equal_byte(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize];
     if vx != opcode.data {
-        Next::Advance(2)
+        Ok(skip(vm))
     } else {
-        Next::Advance(1)
+        Ok(Next::Advance(1))
     }
 }
 
-pub fn skip_on_equal(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn skip_on_equal(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize];
     let vy = vm.registers[opcode.y as usize];
     if vx == vy {
-        Next::Advance(2)
+        Ok(skip(vm))
     } else {
-        Next::Advance(1)
+        Ok(Next::Advance(1))
     }
 }
 
-pub fn skip_on_not_equal(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn skip_on_not_equal(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize];
     let vy = vm.registers[opcode.y as usize];
     if vx != vy {
-        Next::Advance(2)
+        Ok(skip(vm))
+    } else {
+        Ok(Next::Advance(1))
+    }
+}
+
+pub fn store_range(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let registers = register_range(opcode);
+    check_memory(vm, opcode.bytes, vm.i, registers.len())?;
+
+    for (offset, v) in registers.into_iter().enumerate() {
+        let addr = vm.i + offset;
+        let value = vm.registers[v];
+        write(vm, addr, value);
+    }
+
+    Ok(Next::Advance(1))
+}
+
+pub fn read_range(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let registers = register_range(opcode);
+    check_memory(vm, opcode.bytes, vm.i, registers.len())?;
+
+    for (offset, v) in registers.into_iter().enumerate() {
+        let addr = vm.i + offset;
+        vm.registers[v] = vm.ram.read(addr);
+    }
+
+    Ok(Next::Advance(1))
+}
+
+// Registers from Vx to Vy, in descending order when x > y.
+fn register_range(opcode: Opcode) -> Vec<usize> {
+    let (x, y) = (opcode.x as usize, opcode.y as usize);
+    if x <= y {
+        (x..(y + 1)).collect()
     } else {
-        Next::Advance(1)
+        (y..(x + 1)).rev().collect()
     }
 }
 
-pub fn set_byte(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn set_byte(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     vm.registers[opcode.x as usize] = opcode.data;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn add_byte(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn add_byte(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize];
     vm.registers[opcode.x as usize] = vx.wrapping_add(opcode.data);
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn set(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn set(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize];
     vm.registers[opcode.x as usize] = vy;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn or(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn or(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize];
     let vx = vm.registers[opcode.x as usize];
 
     vm.registers[opcode.x as usize] = vx | vy;
 
-    Next::Advance(1)
+    if vm.quirks.vf_reset {
+        vm.registers[0xF] = 0;
+    }
+
+    Ok(Next::Advance(1))
 }
 
-pub fn and(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn and(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize];
     let vx = vm.registers[opcode.x as usize];
 
     vm.registers[opcode.x as usize] = vx & vy;
 
-    Next::Advance(1)
+    if vm.quirks.vf_reset {
+        vm.registers[0xF] = 0;
+    }
+
+    Ok(Next::Advance(1))
 }
 
-pub fn xor(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn xor(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize];
     let vx = vm.registers[opcode.x as usize];
 
     vm.registers[opcode.x as usize] = vx ^ vy;
 
-    Next::Advance(1)
+    if vm.quirks.vf_reset {
+        vm.registers[0xF] = 0;
+    }
+
+    Ok(Next::Advance(1))
 }
 
-pub fn add(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn add(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize] as u16;
     let vx = vm.registers[opcode.x as usize] as u16;
     let add = vx + vy;
@@ -156,10 +409,10 @@ pub fn add(vm: &mut VM, opcode: Opcode) -> Next {
 
     vm.registers[opcode.x as usize] = add as u8;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn sub_x_y(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn sub_x_y(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize];
     let vx = vm.registers[opcode.x as usize];
 
@@ -171,10 +424,10 @@ pub fn sub_x_y(vm: &mut VM, opcode: Opcode) -> Next {
 
     vm.registers[opcode.x as usize] = vx.wrapping_sub(vy);
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn sub_y_x(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn sub_y_x(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vy = vm.registers[opcode.y as usize];
     let vx = vm.registers[opcode.x as usize];
 
@@ -186,183 +439,393 @@ pub fn sub_y_x(vm: &mut VM, opcode: Opcode) -> Next {
 
     vm.registers[opcode.x as usize] = vy.wrapping_sub(vx);
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn shift_right(vm: &mut VM, opcode: Opcode) -> Next {
-    let vy = vm.registers[opcode.y as usize];
+pub fn shift_right(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let vy = shift_source(vm, opcode);
 
     vm.registers[0xF] = vy & 0x1;
     vm.registers[opcode.x as usize] = vy >> 1;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn shift_left(vm: &mut VM, opcode: Opcode) -> Next {
-    let vy = vm.registers[opcode.y as usize];
+pub fn shift_left(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let vy = shift_source(vm, opcode);
 
     vm.registers[0xF] = (vy >> 7) & 0x1;
     vm.registers[opcode.x as usize] = vy << 1;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
+}
+
+fn shift_source(vm: &VM, opcode: Opcode) -> u8 {
+    if vm.quirks.shift_vy {
+        vm.registers[opcode.y as usize]
+    } else {
+        vm.registers[opcode.x as usize]
+    }
 }
 
-pub fn set_i(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn set_i(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     vm.i = opcode.address as usize;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn jump_plus(vm: &mut VM, opcode: Opcode) -> Next {
-    let v0 = vm.registers[0] as u16;
+pub fn long_set_i(vm: &mut VM) -> Result<Next, VmError> {
+    check_memory(vm, 0xF000, vm.pc + 2, 2)?;
+
+    let pc = vm.pc;
+    let high = vm.ram.fetch(pc + 2) as usize;
+    let low = vm.ram.fetch(pc + 3) as usize;
+    vm.i = (high << 8) | low;
+
+    // The address takes a whole word.
+    Ok(Next::Advance(2))
+}
+
+pub fn jump_plus(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let offset = if vm.quirks.jump_vx {
+        vm.registers[opcode.x as usize] as u16
+    } else {
+        vm.registers[0] as u16
+    };
 
-    vm.pc = (v0 + opcode.address) as usize;
+    vm.pc = (offset + opcode.address) as usize;
 
-    Next::Noop
+    Ok(Next::Noop)
 }
 
-pub fn random_mask(vm: &mut VM, opcode: Opcode) -> Next {
-    let mut rng = thread_rng();
-    let rnd: u16 = rng.gen_range(0, 256);
-    let rnd: u8 = rnd as u8;
+pub fn random_mask(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let rnd = vm.rng.next_byte();
 
     vm.registers[opcode.x as usize] = rnd & opcode.data;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn draw(vm: &mut VM, opcode: Opcode) -> Next {
-    let x = vm.registers[opcode.x as usize] as usize;
-    let y = vm.registers[opcode.y as usize] as usize;
-    let i = vm.i;
-    let n = opcode.nibble as usize;
+pub fn draw(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let x = vm.registers[opcode.x as usize] as usize % vm.width;
+    let y = vm.registers[opcode.y as usize] as usize % vm.height;
+
+    // DXY0 draws a 16x16 sprite, two bytes per row.
+    let (rows, columns) = match opcode.nibble {
+        0 => (16, 16),
+        n => (n as usize, 8),
+    };
+    let row_bytes = columns / 8;
+    let sprite_size = rows * row_bytes;
 
-    let mut pixels: Vec<Pixel> = vec![];
+    let planes = vm.planes;
+    let sprites = planes.count_ones() as usize;
+    check_memory(vm, opcode.bytes, vm.i, sprite_size * sprites)?;
 
     vm.registers[0xF] = 0;
-    for (sy, byte) in vm.ram[i..i + n].iter().enumerate() {
-        let dy = (y + sy) % specs::DISPLAY_HEIGHT;
-        for sx in 0usize..8 {
-            let px = (*byte >> (7 - sx)) & 0b00000001;
-            let dx = (x + sx) % specs::DISPLAY_WIDTH;
-            let idx = dy * specs::DISPLAY_WIDTH + dx;
-            vm.gfx[idx] ^= px;
 
-            // Vf is if there was a collision
-            vm.registers[0xF] |= (vm.gfx[idx] == 0 && px == 1) as u8;
+    // Each selected plane takes its own sprite, one after the other.
+    let mut i = vm.i;
+    for plane in [0b01u8, 0b10].iter().filter(|&&p| planes & p != 0) {
+        let sprite: Vec<u8> = (i..i + sprite_size).map(|addr| vm.ram.read(addr)).collect();
+        for (sy, bytes) in sprite.chunks(row_bytes).enumerate() {
+            let dy = y + sy;
+            if dy >= vm.height && vm.quirks.clip_sprites {
+                break;
+            }
+            let dy = dy % vm.height;
+
+            let row = bytes.iter().fold(0u16, |row, &byte| (row << 8) | byte as u16);
+            for sx in 0usize..columns {
+                if (row >> (columns - 1 - sx)) & 0b00000001 == 0 {
+                    continue;
+                }
+
+                let dx = x + sx;
+                if dx >= vm.width && vm.quirks.clip_sprites {
+                    break;
+                }
+                let dx = dx % vm.width;
+                let idx = dy * vm.width + dx;
+
+                // Vf is if there was a collision
+                if vm.gfx[idx] & plane != 0 {
+                    vm.registers[0xF] = 1;
+                }
+                vm.gfx[idx] ^= *plane;
+            }
+        }
 
-            let pixel = Pixel::new(dx as i32, dy as i32, vm.gfx[idx]);
+        i += sprite_size;
+    }
 
-            pixels.push(pixel);
-        }
+    let area = sprite_area(vm, x, y, columns, rows);
+    vm.mark_dirty(area);
+
+    let collision = vm.registers[0xF] == 1;
+    vm.notify(|o| o.on_draw(x, y, rows, collision));
+
+    if vm.quirks.display_wait {
+        vm.vblank_wait = true;
     }
 
-    if let Some(ref bus) = vm.display_bus {
-        bus.send(pixels).unwrap();
+    Ok(Next::Advance(1))
+}
+
+// Screen area covered by a sprite. A sprite that wraps around an edge
+// covers the whole width or height.
+fn sprite_area(vm: &VM, x: usize, y: usize, columns: usize, rows: usize) -> Rect {
+    let clip = vm.quirks.clip_sprites;
+    let (x, width) = match x + columns > vm.width {
+        true if clip => (x, vm.width - x),
+        true => (0, vm.width),
+        false => (x, columns),
+    };
+    let (y, height) = match y + rows > vm.height {
+        true if clip => (y, vm.height - y),
+        true => (0, vm.height),
+        false => (y, rows),
     };
 
-    Next::Advance(1)
+    Rect::new(x, y, width, height)
 }
 
-pub fn skip_on_key_pressed(vm: &mut VM, opcode: Opcode) -> Next {
-    let key = vm.registers[opcode.x as usize] as usize;
+pub fn skip_on_key_pressed(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let key = (vm.registers[opcode.x as usize] & 0xF) as usize;
 
-    if vm.keypad[key] > 0 {
-        vm.keypad[key] -= 1;
-        Next::Advance(2)
+    if vm.keypad[key] {
+        Ok(skip(vm))
     } else {
-        Next::Advance(1)
+        Ok(Next::Advance(1))
     }
 }
 
-pub fn skip_on_key_not_pressed(vm: &mut VM, opcode: Opcode) -> Next {
-    let key = vm.registers[opcode.x as usize] as usize;
+pub fn skip_on_key_not_pressed(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let key = (vm.registers[opcode.x as usize] & 0xF) as usize;
 
-    if vm.keypad[key] == 0 {
-        Next::Advance(2)
+    if vm.keypad[key] {
+        Ok(Next::Advance(1))
     } else {
-        vm.keypad[key] -= 1;
-        Next::Advance(1)
+        Ok(skip(vm))
     }
 }
 
-pub fn store_delay_timer(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn store_delay_timer(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     vm.registers[opcode.x as usize] = vm.dt;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn set_delay_timer(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn set_delay_timer(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     vm.dt = vm.registers[opcode.x as usize];
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn set_sound_timer(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn set_sound_timer(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     vm.st = vm.registers[opcode.x as usize];
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn wait_key(vm: &mut VM, opcode: Opcode) -> Next {
-    let key = vm.keypad.iter().position(|&s| s > 0);
-    match key {
-        Some(value) => {
-            vm.registers[opcode.x as usize] = value as u8;
-            vm.keypad[value] -= 1;
+// Like the COSMAC VIP, it waits until a key is pressed and then released.
+pub fn wait_key(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    match vm.pressed_key {
+        Some(key) if !vm.keypad[key] => {
+            vm.registers[opcode.x as usize] = key as u8;
+            vm.pressed_key = None;
+            vm.key_wait = false;
 
-            Next::Advance(1)
+            Ok(Next::Advance(1))
+        }
+        Some(_) => {
+            vm.idle = true;
+
+            Ok(Next::Noop)
+        }
+        None => {
+            vm.pressed_key = vm.keypad.iter().position(|&down| down);
+            if !vm.key_wait {
+                vm.key_wait = true;
+                vm.notify(|o| o.on_key_wait(opcode.x as usize));
+            }
+            // Nothing changes until a key goes down
+            vm.idle = vm.pressed_key.is_none();
+
+            Ok(Next::Noop)
         }
-        None => Next::Noop,
     }
 }
 
-pub fn add_i(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn add_i(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize] as u16;
     vm.i += vx as usize;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn set_sprite(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn set_sprite(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize] as usize;
     vm.i = specs::SPRITES_ADDR + vx * specs::SPRITE_HEIGHT;
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
+}
+
+pub fn set_big_sprite(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let vx = (vm.registers[opcode.x as usize] & 0xF) as usize;
+    vm.i = specs::BIG_SPRITES_ADDR + vx * specs::BIG_SPRITE_HEIGHT;
+
+    Ok(Next::Advance(1))
+}
+
+pub fn select_plane(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    vm.planes = opcode.x & 0b11;
+
+    Ok(Next::Advance(1))
 }
 
-pub fn bcd(vm: &mut VM, opcode: Opcode) -> Next {
+pub fn load_audio(vm: &mut VM) -> Result<Next, VmError> {
+    let i = vm.i;
+    check_memory(vm, 0xF002, i, specs::AUDIO_PATTERN_SIZE)?;
+
+    for offset in 0..specs::AUDIO_PATTERN_SIZE {
+        vm.audio_pattern[offset] = vm.ram.read(i + offset);
+    }
+
+    Ok(Next::Advance(1))
+}
+
+pub fn set_pitch(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    vm.pitch = vm.registers[opcode.x as usize];
+
+    Ok(Next::Advance(1))
+}
+
+pub fn bcd(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
     let vx = vm.registers[opcode.x as usize];
 
     let b = vx / 100;
     let c = (vx - (b * 100)) / 10;
     let d = vx - (b * 100) - (c * 10);
 
-    vm.ram[vm.i] = b as u8;
-    vm.ram[(vm.i + 1)] = c as u8;
-    vm.ram[(vm.i + 2)] = d as u8;
+    check_memory(vm, opcode.bytes, vm.i, 3)?;
+
+    let i = vm.i;
+    write(vm, i, b);
+    write(vm, i + 1, c);
+    write(vm, i + 2, d);
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn store(vm: &mut VM, opcode: Opcode) -> Next {
-    for v in 0..opcode.x {
+pub fn store(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    check_memory(vm, opcode.bytes, vm.i, opcode.x as usize + 1)?;
+
+    for v in 0..(opcode.x + 1) {
         let pointer = vm.i + v as usize;
-        vm.ram[pointer] = vm.registers[v as usize];
+        let value = vm.registers[v as usize];
+        write(vm, pointer, value);
     }
 
-    vm.i += (opcode.x + 1) as usize;
+    increment_i(vm, opcode);
 
-    Next::Advance(1)
+    Ok(Next::Advance(1))
 }
 
-pub fn read(vm: &mut VM, opcode: Opcode) -> Next {
-    for v in 0..opcode.x {
+pub fn read(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    check_memory(vm, opcode.bytes, vm.i, opcode.x as usize + 1)?;
+
+    for v in 0..(opcode.x + 1) {
         let pointer = vm.i + v as usize;
-        vm.registers[v as usize] = vm.ram[pointer];
+        vm.registers[v as usize] = vm.ram.read(pointer);
+    }
+
+    increment_i(vm, opcode);
+
+    Ok(Next::Advance(1))
+}
+
+pub fn store_flags(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let last = cmp::min(opcode.x as usize, specs::FLAGS_SIZE - 1);
+    for v in 0..(last + 1) {
+        vm.flags[v] = vm.registers[v];
+    }
+
+    Ok(Next::Advance(1))
+}
+
+pub fn read_flags(vm: &mut VM, opcode: Opcode) -> Result<Next, VmError> {
+    let last = cmp::min(opcode.x as usize, specs::FLAGS_SIZE - 1);
+    for v in 0..(last + 1) {
+        vm.registers[v] = vm.flags[v];
+    }
+
+    Ok(Next::Advance(1))
+}
+
+fn increment_i(vm: &mut VM, opcode: Opcode) {
+    match vm.quirks.memory_increment {
+        MemoryIncrement::None => (),
+        MemoryIncrement::X => vm.i += opcode.x as usize,
+        MemoryIncrement::XPlusOne => vm.i += (opcode.x + 1) as usize,
+    }
+}
+
+// Skips the next instruction, which takes two words if it is F000 NNNN.
+fn skip(vm: &mut VM) -> Next {
+    let next = vm.pc + 2;
+    let long = next + 1 < vm.ram.len() && vm.ram.fetch(next) == 0xF0 &&
+               vm.ram.fetch(next + 1) == 0x00;
+    if long {
+        Next::Advance(3)
+    } else {
+        Next::Advance(2)
+    }
+}
+
+// Writes to memory, dropping any decoded instruction that used the byte.
+fn write(vm: &mut VM, addr: usize, value: u8) {
+    vm.ram.write(addr, value);
+    vm.invalidate_decoded(addr);
+    vm.notify(|o| o.on_memory_write(addr, value));
+}
+
+// Makes sure that `len` bytes starting at `addr` are inside the RAM.
+fn check_memory(vm: &VM, opcode: u16, addr: usize, len: usize) -> Result<(), VmError> {
+    if addr + len > vm.ram.len() {
+        let address = cmp::max(addr, vm.ram.len());
+        return Err(VmError::InvalidAddress { pc: vm.pc, opcode, address });
     }
 
-    vm.i += (opcode.x + 1) as usize;
+    Ok(())
+}
+
+// Moves the selected planes of the screen by (dx, dy) pixels.
+// Whatever falls off the edges is lost.
+fn scroll(vm: &mut VM, dx: isize, dy: isize) {
+    let (width, height) = (vm.width as isize, vm.height as isize);
+    let planes = vm.planes;
+    let previous = vm.gfx.clone();
+
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = (x - dx, y - dy);
+            let moved = if sx >= 0 && sx < width && sy >= 0 && sy < height {
+                previous[(sy * width + sx) as usize]
+            } else {
+                0
+            };
+
+            let idx = (y * width + x) as usize;
+            vm.gfx[idx] = (previous[idx] & !planes) | (moved & planes);
+        }
+    }
+    vm.mark_screen_dirty();
+}
 
-    Next::Advance(1)
+fn set_resolution(vm: &mut VM, width: usize, height: usize) {
+    vm.width = width;
+    vm.height = height;
+    vm.gfx = vec![0; width * height];
+    vm.mark_screen_dirty();
 }
@@ -0,0 +1,429 @@
+// Save states
+//
+// A snapshot is a copy of everything that changes while a program runs.
+// The configuration of the VM (quirks, CPU rate, clock, display bus...)
+// is not part of it, it belongs to whoever restores the snapshot.
+//
+// The RAM is kept in pages. A snapshot taken after another one shares the
+// pages that didn't change, so the rewind history only stores what each
+// frame wrote.
+//
+// The binary format is a header with a magic number and a version,
+// followed by every field in a fixed order. Integers are big endian.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use specs;
+use vm::{VM, State, VmError};
+
+const MAGIC: &[u8; 4] = b"J8SS";
+pub const VERSION: u8 = 3;
+
+// Written when FX0A isn't waiting for a key release
+const NO_KEY: u8 = 0xFF;
+
+const PAGE_SIZE: usize = 256;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Snapshot {
+    ram: Vec<Arc<Vec<u8>>>, // Pages, shared between snapshots when they don't change
+    registers: [u8; specs::GENERAL_REGISTERS_SIZE],
+    stack: Vec<u16>,
+    keypad: [bool; specs::KEYPAD_SIZE],
+    gfx: Arc<Vec<u8>>,
+    flags: [u8; specs::FLAGS_SIZE],
+
+    width: usize,
+    height: usize,
+    planes: u8,
+
+    audio_pattern: [u8; specs::AUDIO_PATTERN_SIZE],
+    pitch: u8,
+
+    i: usize,
+    pressed_key: Option<usize>,
+    dt: u8,
+    st: u8,
+    pc: usize,
+    sp: usize,
+
+    cpu_budget: u64,
+    cycle_budget: i64,
+    vblank_wait: bool,
+    state: State,
+    rng: Option<u64>,
+}
+
+impl Snapshot {
+    pub fn take(vm: &VM) -> Snapshot {
+        Snapshot {
+            ram: vm.ram.as_slice().chunks(PAGE_SIZE).map(|page| Arc::new(page.to_vec())).collect(),
+            gfx: Arc::new(vm.gfx.as_slice().to_vec()),
+            ..Snapshot::take_registers(vm)
+        }
+    }
+
+    // Everything but the memory and the screen.
+    fn take_registers(vm: &VM) -> Snapshot {
+        Snapshot {
+            ram: vec![],
+            registers: vm.registers,
+            stack: vm.stack.to_vec(),
+            keypad: vm.keypad,
+            gfx: Arc::new(vec![]),
+            flags: vm.flags,
+
+            width: vm.width,
+            height: vm.height,
+            planes: vm.planes,
+
+            audio_pattern: vm.audio_pattern,
+            pitch: vm.pitch,
+
+            i: vm.i,
+            pressed_key: vm.pressed_key,
+            dt: vm.dt,
+            st: vm.st,
+            pc: vm.pc,
+            sp: vm.sp,
+
+            cpu_budget: vm.cpu_budget,
+            cycle_budget: vm.cycle_budget,
+            vblank_wait: vm.vblank_wait,
+            state: vm.state,
+            rng: vm.rng.state(),
+        }
+    }
+
+    /// Copies the snapshot into the VM. The snapshot must come from a VM
+    /// with the same memory and stack sizes.
+    pub fn restore(&self, vm: &mut VM) -> Result<(), VmError> {
+        if self.ram_size() != vm.ram.len() {
+            return Err(VmError::InvalidSaveState("the RAM size doesn't match"));
+        }
+        if self.stack.len() != vm.stack.len() {
+            return Err(VmError::InvalidSaveState("the stack size doesn't match"));
+        }
+
+        for (page, bytes) in vm.ram.as_mut_slice().chunks_mut(PAGE_SIZE).zip(&self.ram) {
+            page.copy_from_slice(bytes);
+        }
+        vm.registers = self.registers;
+        vm.stack.copy_from_slice(&self.stack);
+        vm.keypad = self.keypad;
+        vm.gfx = self.gfx.to_vec();
+        vm.flags = self.flags;
+
+        vm.width = self.width;
+        vm.height = self.height;
+        vm.planes = self.planes;
+
+        vm.audio_pattern = self.audio_pattern;
+        vm.pitch = self.pitch;
+
+        vm.i = self.i;
+        vm.pressed_key = self.pressed_key;
+        vm.key_wait = false;
+        vm.dt = self.dt;
+        vm.st = self.st;
+        vm.pc = self.pc;
+        vm.sp = self.sp;
+
+        vm.cpu_budget = self.cpu_budget;
+        vm.cycle_budget = self.cycle_budget;
+        vm.vblank_wait = self.vblank_wait;
+        vm.state = self.state;
+        if let Some(state) = self.rng {
+            vm.rng.set_state(state);
+        }
+
+        Ok(())
+    }
+
+    /// Same as `take`, but reuses the pages of memory and the screen of the
+    /// previous snapshot when they haven't changed, which keeps a long
+    /// history of frames small.
+    pub fn take_after(vm: &VM, previous: &Snapshot) -> Snapshot {
+        if previous.ram_size() != vm.ram.len() {
+            return Snapshot::take(vm);
+        }
+
+        let ram = vm.ram
+            .as_slice()
+            .chunks(PAGE_SIZE)
+            .zip(&previous.ram)
+            .map(|(page, shared)| {
+                if page == &shared[..] {
+                    shared.clone()
+                } else {
+                    Arc::new(page.to_vec())
+                }
+            })
+            .collect();
+        let gfx = if vm.gfx == *previous.gfx {
+            previous.gfx.clone()
+        } else {
+            Arc::new(vm.gfx.clone())
+        };
+
+        Snapshot { ram, gfx, ..Snapshot::take_registers(vm) }
+    }
+
+    fn ram_size(&self) -> usize {
+        self.ram.iter().map(|page| page.len()).sum()
+    }
+
+    pub fn write_to(&self, writer: &mut dyn Write) -> Result<(), VmError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        write_u32(writer, self.ram_size() as u32)?;
+        for page in &self.ram {
+            writer.write_all(page)?;
+        }
+        writer.write_all(&self.registers)?;
+        writer.write_all(&[self.stack.len() as u8])?;
+        for addr in &self.stack {
+            write_u16(writer, *addr)?;
+        }
+        for down in &self.keypad {
+            writer.write_all(&[*down as u8])?;
+        }
+
+        write_u16(writer, self.width as u16)?;
+        write_u16(writer, self.height as u16)?;
+        writer.write_all(&self.gfx)?;
+        writer.write_all(&self.flags)?;
+        writer.write_all(&[self.planes])?;
+
+        writer.write_all(&self.audio_pattern)?;
+        writer.write_all(&[self.pitch])?;
+
+        write_u32(writer, self.i as u32)?;
+        let pressed_key = match self.pressed_key {
+            Some(key) => key as u8,
+            None => NO_KEY,
+        };
+        writer.write_all(&[pressed_key])?;
+        writer.write_all(&[self.dt, self.st])?;
+        write_u32(writer, self.pc as u32)?;
+        writer.write_all(&[self.sp as u8])?;
+
+        write_u64(writer, self.cpu_budget)?;
+        write_u64(writer, self.cycle_budget as u64)?;
+        writer.write_all(&[self.vblank_wait as u8])?;
+        let state = match self.state {
+            State::Running => 0,
+            State::Exited => 1,
+            State::Halted => 2,
+        };
+        writer.write_all(&[state])?;
+        match self.rng {
+            Some(rng) => {
+                writer.write_all(&[1])?;
+                write_u64(writer, rng)?;
+            }
+            None => writer.write_all(&[0])?,
+        };
+
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> Result<Snapshot, VmError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(VmError::InvalidSaveState("not a save state"));
+        }
+
+        let version = read_u8(reader)?;
+        if version != VERSION {
+            return Err(VmError::SaveStateVersion { version });
+        }
+
+        let ram_size = read_u32(reader)? as usize;
+        if ram_size > specs::XO_RAM_SIZE {
+            return Err(VmError::InvalidSaveState("the RAM is too large"));
+        }
+        let ram = read_bytes(reader, ram_size)?;
+
+        let mut registers = [0; specs::GENERAL_REGISTERS_SIZE];
+        reader.read_exact(&mut registers)?;
+
+        let stack_size = read_u8(reader)? as usize;
+        let mut stack = Vec::with_capacity(stack_size);
+        for _ in 0..stack_size {
+            stack.push(read_u16(reader)?);
+        }
+
+        let mut keypad = [false; specs::KEYPAD_SIZE];
+        for down in keypad.iter_mut() {
+            *down = read_u8(reader)? != 0;
+        }
+
+        let width = read_u16(reader)? as usize;
+        let height = read_u16(reader)? as usize;
+        let valid_resolution = (width, height) ==
+                               (specs::DISPLAY_WIDTH, specs::DISPLAY_HEIGHT) ||
+                               (width, height) ==
+                               (specs::HIRES_CHIP8_DISPLAY_WIDTH,
+                                specs::HIRES_CHIP8_DISPLAY_HEIGHT) ||
+                               (width, height) ==
+                               (specs::HIRES_DISPLAY_WIDTH, specs::HIRES_DISPLAY_HEIGHT);
+        if !valid_resolution {
+            return Err(VmError::InvalidSaveState("unknown display resolution"));
+        }
+        let gfx = read_bytes(reader, width * height)?;
+
+        let mut flags = [0; specs::FLAGS_SIZE];
+        reader.read_exact(&mut flags)?;
+        let planes = read_u8(reader)?;
+        if planes & !0b11 != 0 {
+            return Err(VmError::InvalidSaveState("unknown bitplanes"));
+        }
+
+        let mut audio_pattern = [0; specs::AUDIO_PATTERN_SIZE];
+        reader.read_exact(&mut audio_pattern)?;
+        let pitch = read_u8(reader)?;
+
+        let i = read_u32(reader)? as usize;
+        let pressed_key = match read_u8(reader)? {
+            NO_KEY => None,
+            key if (key as usize) < specs::KEYPAD_SIZE => Some(key as usize),
+            _ => return Err(VmError::InvalidSaveState("unknown key")),
+        };
+        let dt = read_u8(reader)?;
+        let st = read_u8(reader)?;
+        let pc = read_u32(reader)? as usize;
+        let sp = read_u8(reader)? as usize;
+        if pc >= ram_size {
+            return Err(VmError::InvalidSaveState("the PC is out of memory"));
+        }
+        if sp > stack_size {
+            return Err(VmError::InvalidSaveState("the stack pointer is out of the stack"));
+        }
+
+        let cpu_budget = read_u64(reader)?;
+        let cycle_budget = read_u64(reader)? as i64;
+        let vblank_wait = read_u8(reader)? != 0;
+        let state = match read_u8(reader)? {
+            0 => State::Running,
+            1 => State::Exited,
+            2 => State::Halted,
+            _ => return Err(VmError::InvalidSaveState("unknown VM state")),
+        };
+        let rng = match read_u8(reader)? {
+            0 => None,
+            _ => Some(read_u64(reader)?),
+        };
+
+        Ok(Snapshot {
+            ram: ram.chunks(PAGE_SIZE).map(|page| Arc::new(page.to_vec())).collect(),
+            registers,
+            stack,
+            keypad,
+            gfx: Arc::new(gfx),
+            flags,
+
+            width,
+            height,
+            planes,
+
+            audio_pattern,
+            pitch,
+
+            i,
+            pressed_key,
+            dt,
+            st,
+            pc,
+            sp,
+
+            cpu_budget,
+            cycle_budget,
+            vblank_wait,
+            state,
+            rng,
+        })
+    }
+}
+
+fn write_u16(writer: &mut dyn Write, value: u16) -> Result<(), VmError> {
+    writer.write_all(&[(value >> 8) as u8, value as u8])?;
+
+    Ok(())
+}
+
+fn write_u32(writer: &mut dyn Write, value: u32) -> Result<(), VmError> {
+    write_u16(writer, (value >> 16) as u16)?;
+    write_u16(writer, value as u16)
+}
+
+fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), VmError> {
+    write_u32(writer, (value >> 32) as u32)?;
+    write_u32(writer, value as u32)
+}
+
+fn read_bytes(reader: &mut dyn Read, len: usize) -> Result<Vec<u8>, VmError> {
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn read_u8(reader: &mut dyn Read) -> Result<u8, VmError> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+
+    Ok(byte[0])
+}
+
+fn read_u16(reader: &mut dyn Read) -> Result<u16, VmError> {
+    let high = read_u8(reader)? as u16;
+    let low = read_u8(reader)? as u16;
+
+    Ok((high << 8) | low)
+}
+
+fn read_u32(reader: &mut dyn Read) -> Result<u32, VmError> {
+    let high = read_u16(reader)? as u32;
+    let low = read_u16(reader)? as u32;
+
+    Ok((high << 16) | low)
+}
+
+fn read_u64(reader: &mut dyn Read) -> Result<u64, VmError> {
+    let high = read_u32(reader)? as u64;
+    let low = read_u32(reader)? as u64;
+
+    Ok((high << 32) | low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_share_the_pages_that_did_not_change() {
+        let mut vm = VM::boot();
+        vm.set_ram_size(specs::XO_RAM_SIZE);
+        let first = Snapshot::take(&vm);
+
+        vm.ram[0x1234] = 0xAB;
+        let second = Snapshot::take_after(&vm, &first);
+
+        let shared = first.ram
+            .iter()
+            .zip(&second.ram)
+            .filter(|&(a, b)| Arc::ptr_eq(a, b))
+            .count();
+        assert_eq!(specs::XO_RAM_SIZE / PAGE_SIZE - 1, shared);
+        assert!(Arc::ptr_eq(&first.gfx, &second.gfx));
+
+        let mut copy = VM::boot();
+        copy.set_ram_size(specs::XO_RAM_SIZE);
+        second.restore(&mut copy).unwrap();
+        assert_eq!(0xAB, copy.ram[0x1234]);
+    }
+}
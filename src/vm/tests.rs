@@ -1,30 +1,39 @@
-#[cfg(test)]
-
 use instructions::Instruction;
 use keypad::Key;
+use display::{self, FramebufferSink, Rect, Rgb};
+use image::{GifRecorder, ImageFormat, Recording, Screenshot};
+use quirks;
+use random::RandomSource;
 use specs::*;
-use vm::{VM, Tick};
+use vm::{VM, VmBuilder, VmConfig, Fonts, VmError, VmObserver, State, UnknownOpcodePolicy};
+use audio::AudioSink;
+use clock::{ManualClock, FastForwardClock, RealtimeClock};
+use memory::Access;
+use timing::{Timing, VIP_CYCLES_PER_FRAME, VIP_DISPLAY_CYCLES};
+use instructions::Opcode;
 use std::io::Cursor;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::BufRead;
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[test]
 fn loads_an_empty_vm_by_default() {
     let vm = VM::boot();
 
     assert_eq!(RAM_SIZE, vm.ram.len());
-    assert_eq!([0 as u8; GENERAL_REGISTERS_SIZE], vm.registers);
-    assert_eq!([0 as u16; STACK_SIZE], vm.stack);
-    assert_eq!([0 as u8; KEYPAD_SIZE], vm.keypad);
+    assert_eq!([0_u8; GENERAL_REGISTERS_SIZE], vm.registers);
+    assert_eq!(vec![0_u16; STACK_SIZE], vm.stack);
+    assert_eq!([false; KEYPAD_SIZE], vm.keypad);
     assert_eq!(DISPLAY_PIXELS, vm.gfx.len());
 
     assert_eq!(PROGRAM_START, vm.pc);
-    assert_eq!(0 as usize, vm.i);
-    assert_eq!(0 as usize, vm.sp);
-    assert_eq!(0 as u8, vm.dt);
-    assert_eq!(0 as u8, vm.st);
+    assert_eq!(0_usize, vm.i);
+    assert_eq!(0_usize, vm.sp);
+    assert_eq!(0_u8, vm.dt);
+    assert_eq!(0_u8, vm.st);
 }
 
 #[test]
@@ -50,18 +59,43 @@ fn loads_the_sprites() {
     assert_eq!([0xF0, 0x80, 0xF0, 0x80, 0x80], vm.ram[75..80]); // F
 }
 
+#[test]
+fn loads_the_big_sprites() {
+    let mut vm = VM::boot();
+    vm.load_sprites();
+
+    let range = BIG_SPRITES_ADDR..(BIG_SPRITES_ADDR + BIG_SPRITES_SIZE);
+    assert_eq!(BIG_SPRITES[..], vm.ram[range]);
+}
+
 #[test]
 fn loads_a_rom() {
     let rom: Vec<u8> = vec![0xA; RAM_SIZE - PROGRAM_START - 10];
     let mut reader = Cursor::new(rom);
 
     let mut vm = VM::boot();
-    vm.load_rom(&mut reader);
+    vm.load_rom(&mut reader).unwrap();
     let range = PROGRAM_START..(RAM_SIZE - 10);
 
     assert!(vm.ram[range].iter().all(|&x| x == 0xA));
 }
 
+#[test]
+fn fails_to_load_a_rom_too_large_for_the_memory() {
+    let rom: Vec<u8> = vec![0xA; RAM_SIZE - PROGRAM_START + 1];
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+
+    match vm.load_rom(&mut reader) {
+        Err(VmError::RomTooLarge { size, capacity }) => {
+            assert_eq!(RAM_SIZE - PROGRAM_START + 1, size);
+            assert_eq!(RAM_SIZE - PROGRAM_START, capacity);
+        }
+        other => panic!("Unexpected result {:?}", other.map(|_| ())),
+    }
+}
+
 #[test]
 fn inits_the_clock() {
     let mut vm = VM::boot();
@@ -75,8 +109,10 @@ fn the_clock_ticks() {
     let mut vm = VM::boot();
     vm.init_clock();
 
-    let clock = vm.clock.unwrap();
-    assert_eq!(Tick, clock.recv().unwrap());
+    thread::sleep(Duration::from_millis(50));
+
+    let mut clock = vm.clock.unwrap();
+    assert!(clock.pending_frames() > 0);
 }
 
 #[test]
@@ -85,16 +121,14 @@ fn loads_a_rom_from_a_file() {
     let mut rom = File::open(rom_path).unwrap();
 
     let mut vm = VM::boot();
-    vm.load_rom(&mut rom);
+    vm.load_rom(&mut rom).unwrap();
 
     let txt_path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/chip_8_logo.txt");
     let txt = File::open(txt_path).unwrap();
     let file = BufReader::new(&txt);
-    let mut index = PROGRAM_START;
-    for line in file.lines() {
+    for (index, line) in (PROGRAM_START..).zip(file.lines()) {
         let l = line.unwrap();
         let value = format!("{:#X}", vm.ram[index]);
-        index += 1;
         assert_eq!(l, value);
     }
 }
@@ -116,23 +150,24 @@ fn advances_the_pc_x_times() {
 }
 
 #[test]
-fn sets_a_key() {
+fn holds_a_key_down() {
     let mut vm = VM::boot();
-    let key = Key::A;
 
-    vm.set_key(key);
+    vm.key_down(Key::A);
 
-    assert_eq!(1, vm.keypad[0xA]);
+    assert!(vm.keypad[0xA]);
+    assert!(vm.is_key_down(Key::A));
 }
 
 #[test]
-fn sets_a_key_more_than_once() {
+fn releases_a_key() {
     let mut vm = VM::boot();
 
-    vm.set_key(Key::A);
-    vm.set_key(Key::A);
+    vm.key_down(Key::A);
+    vm.key_up(Key::A);
 
-    assert_eq!(2, vm.keypad[0xA]);
+    assert!(!vm.keypad[0xA]);
+    assert!(!vm.is_key_down(Key::A));
 }
 
 #[test]
@@ -141,18 +176,19 @@ fn cycles_on_clock_tick() {
     let mut rom = File::open(rom_path).unwrap();
 
     let mut vm = VM::boot();
-    vm.load_rom(&mut rom);
+    vm.load_rom(&mut rom).unwrap();
+    vm.set_instructions_per_frame(1);
 
     // We need to force the clock tick
-    let (ticker, clock): (Sender<Tick>, Receiver<Tick>) = channel();
-    vm.clock = Some(clock);
-    ticker.send(Tick).unwrap();
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
 
-    vm.gfx = [1; DISPLAY_PIXELS];
+    vm.gfx = vec![1; DISPLAY_PIXELS];
     vm.dt = 1;
     vm.st = 1;
 
-    vm.cycle();
+    vm.cycle().unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
     assert_eq!(0, vm.dt);
@@ -161,23 +197,171 @@ fn cycles_on_clock_tick() {
     assert!(vm.gfx.iter().all(|&x| x == 0));
 }
 
+#[test]
+fn executes_the_instructions_of_a_frame_on_clock_tick() {
+    // ADD V0, 1 over and over
+    let rom: Vec<u8> = [0x70, 0x01].iter().cloned().cycle().take(100).collect();
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+    vm.set_instructions_per_frame(10);
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    vm.dt = 5;
+    vm.st = 5;
+
+    vm.cycle().unwrap();
+
+    assert_eq!(10, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 20, vm.pc);
+    // The timers only count down once per frame
+    assert_eq!(4, vm.dt);
+    assert_eq!(4, vm.st);
+}
+
+#[test]
+fn keeps_the_cpu_frequency_across_frames() {
+    let rom: Vec<u8> = [0x70, 0x01].iter().cloned().cycle().take(100).collect();
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+    // 1.5 instructions per frame
+    vm.set_cpu_frequency(TIMER_FREQUENCY * 3 / 2);
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    for _ in 0..4 {
+        clock.advance(1);
+    }
+
+    vm.dt = 10;
+
+    vm.cycle().unwrap();
+
+    assert_eq!(6, vm.registers[0x0]);
+    assert_eq!(6, vm.dt);
+}
+
+#[test]
+fn cycles_every_frame_the_clock_hands_out() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(1);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+    vm.set_clock(Box::new(FastForwardClock::new(4)));
+
+    vm.cycle().unwrap();
+    assert_eq!(2, vm.registers[0x0]);
+
+    vm.cycle().unwrap();
+    assert_eq!(4, vm.registers[0x0]);
+}
+
+#[test]
+fn fails_to_cycle_when_the_pc_leaves_the_memory() {
+    let mut vm = VM::boot();
+    vm.pc = RAM_SIZE - 1;
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    match vm.cycle() {
+        Err(VmError::InvalidPc { pc }) => assert_eq!(RAM_SIZE - 1, pc),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn halts_on_unknown_instructions_by_default() {
+    let rom: Vec<u8> = vec![0x70, 0x01, 0xFF, 0xFF, 0x70, 0x01];
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+    vm.set_instructions_per_frame(3);
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    match vm.cycle() {
+        Err(VmError::UnknownOpcode { pc, opcode }) => {
+            assert_eq!(PROGRAM_START + 2, pc);
+            assert_eq!(0xFFFF, opcode);
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert_eq!(1, vm.registers[0x0]);
+}
+
+#[test]
+fn skips_unknown_instructions_with_skip_policy() {
+    let rom: Vec<u8> = vec![0x70, 0x01, 0xFF, 0xFF, 0x70, 0x01];
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+    vm.set_instructions_per_frame(3);
+    vm.set_unknown_opcode_policy(UnknownOpcodePolicy::Skip);
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    vm.cycle().unwrap();
+
+    assert_eq!(2, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 6, vm.pc);
+}
+
+#[test]
+fn traps_unknown_instructions_with_trap_policy() {
+    let rom: Vec<u8> = vec![0x70, 0x01, 0xFF, 0xFF, 0x70, 0x01];
+    let mut reader = Cursor::new(rom);
+
+    let trapped = Arc::new(Mutex::new(vec![]));
+    let log = trapped.clone();
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+    vm.set_instructions_per_frame(3);
+    vm.set_unknown_opcode_policy(UnknownOpcodePolicy::Trap(Box::new(move |pc, opcode| {
+        log.lock().unwrap().push((pc, opcode));
+        Ok(())
+    })));
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    vm.cycle().unwrap();
+
+    assert_eq!(vec![(PROGRAM_START + 2, Opcode::new(0xFFFF))], *trapped.lock().unwrap());
+    assert_eq!(2, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 6, vm.pc);
+}
+
 #[test]
 fn does_not_cycle_without_tick() {
     let rom_path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/chip_8_logo.rom");
     let mut rom = File::open(rom_path).unwrap();
 
     let mut vm = VM::boot();
-    vm.load_rom(&mut rom);
+    vm.load_rom(&mut rom).unwrap();
 
-    let (_ticker, clock): (Sender<Tick>, Receiver<Tick>) = channel();
-    vm.clock = Some(clock);
+    vm.set_clock(Box::new(ManualClock::new()));
     // We don't send anything to the channel
 
-    vm.gfx = [1; DISPLAY_PIXELS];
+    vm.gfx = vec![1; DISPLAY_PIXELS];
     vm.dt = 1;
     vm.st = 1;
 
-    vm.cycle();
+    vm.cycle().unwrap();
 
     assert_eq!(PROGRAM_START, vm.pc);
     assert_eq!(1, vm.dt);
@@ -190,9 +374,9 @@ fn executes_clear_instruction() {
     let instruction = Instruction::decode(0x00E0).unwrap();
     let mut vm = VM::boot();
 
-    vm.gfx = [1; DISPLAY_PIXELS];
+    vm.gfx = vec![1; DISPLAY_PIXELS];
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert!(vm.gfx.iter().all(|&x| x == 0));
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -201,27 +385,134 @@ fn executes_clear_instruction() {
 #[test]
 fn executes_return_instruction() {
     let instruction = Instruction::decode(0x00EE).unwrap();
-    let mut stack = [0; 16];
-    stack[1] = 0xA1;
+    let mut stack = vec![0; 16];
+    stack[0] = 0xA1;
 
     let mut vm = VM::boot();
     vm.stack = stack;
     vm.sp = 1;
     vm.pc = 0;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xA1 + 2, vm.pc);
     assert_eq!(0, vm.sp);
 }
 
+#[test]
+fn executes_scroll_down_instruction() {
+    let instruction = Instruction::decode(0x00C2).unwrap();
+    let mut vm = VM::boot();
+
+    vm.gfx[3] = 1;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0, vm.gfx[3]);
+    assert_eq!(1, vm.gfx[2 * DISPLAY_WIDTH + 3]);
+    assert_eq!(1, vm.gfx.iter().filter(|&&p| p == 1).count());
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_scroll_right_instruction() {
+    let instruction = Instruction::decode(0x00FB).unwrap();
+    let mut vm = VM::boot();
+
+    vm.gfx[DISPLAY_WIDTH + 1] = 1;
+    vm.gfx[DISPLAY_WIDTH - 1] = 1; // Falls off the screen
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(1, vm.gfx[DISPLAY_WIDTH + 5]);
+    assert_eq!(1, vm.gfx.iter().filter(|&&p| p == 1).count());
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_scroll_left_instruction() {
+    let instruction = Instruction::decode(0x00FC).unwrap();
+    let mut vm = VM::boot();
+
+    vm.gfx[DISPLAY_WIDTH + 5] = 1;
+    vm.gfx[DISPLAY_WIDTH] = 1; // Falls off the screen
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(1, vm.gfx[DISPLAY_WIDTH + 1]);
+    assert_eq!(1, vm.gfx.iter().filter(|&&p| p == 1).count());
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_exit_instruction() {
+    let instruction = Instruction::decode(0x00FD).unwrap();
+    let mut vm = VM::boot();
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(State::Exited, vm.state());
+    assert_eq!(PROGRAM_START, vm.pc);
+}
+
+#[test]
+fn does_not_cycle_after_exit() {
+    // EXIT followed by ADD V0, 1
+    let rom: Vec<u8> = vec![0x00, 0xFD, 0x70, 0x01];
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    vm.cycle().unwrap();
+
+    assert_eq!(0, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START, vm.pc);
+}
+
+#[test]
+fn executes_high_res_and_low_res_instructions() {
+    let mut vm = VM::boot();
+
+    vm.exec(Instruction::decode(0x00FF).unwrap()).unwrap();
+
+    assert_eq!((HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT), vm.resolution());
+    assert_eq!(HIRES_DISPLAY_PIXELS, vm.gfx.len());
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+
+    vm.exec(Instruction::decode(0x00FE).unwrap()).unwrap();
+
+    assert_eq!((DISPLAY_WIDTH, DISPLAY_HEIGHT), vm.resolution());
+    assert_eq!(DISPLAY_PIXELS, vm.gfx.len());
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+}
+
+#[test]
+fn fails_to_execute_return_instruction_with_an_empty_stack() {
+    let instruction = Instruction::decode(0x00EE).unwrap();
+
+    let mut vm = VM::boot();
+
+    match vm.exec(instruction) {
+        Err(VmError::StackUnderflow { pc, opcode }) => {
+            assert_eq!(PROGRAM_START, pc);
+            assert_eq!(0x00EE, opcode);
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
 #[test]
 fn executes_jump_instruction() {
     let instruction = Instruction::decode(0x1FA1).unwrap();
 
     let mut vm = VM::boot();
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x0FA1, vm.pc);
 }
@@ -233,11 +524,49 @@ fn executes_call_instruction() {
     let mut vm = VM::boot();
     vm.pc = 0x0123;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x0FA1, vm.pc);
     assert_eq!(1, vm.sp);
-    assert_eq!(0x0123, vm.stack[1]);
+    assert_eq!(0x0123, vm.stack[0]);
+}
+
+#[test]
+fn fails_to_execute_call_instruction_with_a_full_stack() {
+    let mut vm = VM::boot();
+    vm.sp = STACK_SIZE;
+
+    match vm.exec(Instruction::decode(0x2FA1).unwrap()) {
+        Err(VmError::StackOverflow { pc, opcode }) => {
+            assert_eq!(PROGRAM_START, pc);
+            assert_eq!(0x2FA1, opcode);
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert_eq!(STACK_SIZE, vm.sp);
+}
+
+#[test]
+fn makes_as_many_nested_calls_as_the_stack_depth() {
+    let mut vm = VM::boot();
+    // CALL 0x202; CALL 0x204; ... each one calls the next
+    for n in 0..STACK_SIZE {
+        let address = PROGRAM_START + 2 * (n + 1);
+        vm.exec(Instruction::decode(0x2000 | address as u16).unwrap()).unwrap();
+    }
+    assert_eq!(STACK_SIZE, vm.sp);
+
+    match vm.exec(Instruction::decode(0x2FA1).unwrap()) {
+        Err(VmError::StackOverflow { .. }) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    // And returns from all of them
+    for _ in 0..STACK_SIZE {
+        vm.exec(Instruction::decode(0x00EE).unwrap()).unwrap();
+    }
+    assert_eq!(0, vm.sp);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
 }
 
 #[test]
@@ -248,7 +577,7 @@ fn executes_skip_on_equal_byte_instruction_with_equal_values() {
 
     vm.registers[0x2] = 0xAB; // same value as the fixture
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 4, vm.pc);
 }
@@ -261,7 +590,7 @@ fn executes_skip_on_equal_byte_instruction_with_diff_values() {
 
     vm.registers[0x2] = 0xAF; // different value as the fixture
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
@@ -274,7 +603,7 @@ fn executes_skip_on_not_equal_byte_instruction_with_equal_values() {
 
     vm.registers[0x2] = 0xAB; // same value as the fixture
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
@@ -287,7 +616,7 @@ fn executes_skip_on_not_equal_byte_instruction_with_diff_values() {
 
     vm.registers[0x2] = 0xAF; // different value as the fixture
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 4, vm.pc);
 }
@@ -301,7 +630,7 @@ fn executes_skip_on_equal_instruction_with_equal_values() {
     vm.registers[0x2] = 0xAB;
     vm.registers[0x8] = 0xAB;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 4, vm.pc);
 }
@@ -315,7 +644,7 @@ fn executes_skip_on_equal_instruction_with_diff_values() {
     vm.registers[0x2] = 0xAF;
     vm.registers[0x8] = 0x12;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
@@ -329,7 +658,7 @@ fn executes_skip_on_not_equal_instruction_with_equal_values() {
     vm.registers[0x2] = 0xAB;
     vm.registers[0x8] = 0xAB;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
@@ -343,7 +672,7 @@ fn executes_skip_on_not_equal_instruction_with_diff_values() {
     vm.registers[0x2] = 0xAF;
     vm.registers[0x8] = 0x12;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 4, vm.pc);
 }
@@ -354,7 +683,7 @@ fn executes_set_byte_instruction() {
 
     let mut vm = VM::boot();
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xAB, vm.registers[0x2]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -368,7 +697,7 @@ fn executes_add_byte_instruction() {
 
     vm.registers[0x2] = 0x11;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x22, vm.registers[0x2]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -383,7 +712,7 @@ fn executes_set_instruction() {
     vm.registers[0x2] = 0x11;
     vm.registers[0x1] = 0xAB;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xAB, vm.registers[0x2]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -398,7 +727,7 @@ fn executes_or_instruction() {
     vm.registers[0x2] = 0x11; // Vx
     vm.registers[0x1] = 0xAB; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xBB, vm.registers[0x2]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -413,7 +742,7 @@ fn executes_and_instruction() {
     vm.registers[0x2] = 0x11; // Vx
     vm.registers[0x1] = 0xAB; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x01, vm.registers[0x2]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -428,7 +757,7 @@ fn executes_xor_instruction() {
     vm.registers[0x2] = 0x11; // Vx
     vm.registers[0x1] = 0xAB; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xBA, vm.registers[0x2]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -443,7 +772,7 @@ fn executes_add_instruction_with_carry() {
     vm.registers[0x2] = 0x83; // Vx
     vm.registers[0x1] = 0x7D; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x0, vm.registers[0x2]);
     assert_eq!(0x1, vm.registers[0xF]);
@@ -459,7 +788,7 @@ fn executes_add_instruction_without_carry() {
     vm.registers[0x2] = 0x82; // Vx
     vm.registers[0x1] = 0x7D; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xFF, vm.registers[0x2]);
     assert_eq!(0x0, vm.registers[0xF]);
@@ -475,7 +804,7 @@ fn executes_sub_x_y_instruction_with_borrow() {
     vm.registers[0x2] = 0x7D; // Vx
     vm.registers[0x1] = 0x82; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xFB, vm.registers[0x2]);
     assert_eq!(0x0, vm.registers[0xF]);
@@ -491,7 +820,7 @@ fn executes_sub_x_y_instruction_without_borrow() {
     vm.registers[0x2] = 0x82; // Vx
     vm.registers[0x1] = 0x7D; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x5, vm.registers[0x2]);
     assert_eq!(0x1, vm.registers[0xF]);
@@ -507,7 +836,7 @@ fn executes_sub_y_x_instruction_with_borrow() {
     vm.registers[0x2] = 0x82; // Vx
     vm.registers[0x1] = 0x7D; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xFB, vm.registers[0x2]);
     assert_eq!(0x0, vm.registers[0xF]);
@@ -523,7 +852,7 @@ fn executes_sub_y_x_instruction_without_borrow() {
     vm.registers[0x2] = 0x7D; // Vx
     vm.registers[0x1] = 0x82; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x5, vm.registers[0x2]);
     assert_eq!(0x1, vm.registers[0xF]);
@@ -539,7 +868,7 @@ fn executes_shift_right_instruction_with_carry() {
     vm.registers[0x2] = 0x7D; // Vx
     vm.registers[0x1] = 0xFF; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x7F, vm.registers[0x2]);
     assert_eq!(0x1, vm.registers[0xF]);
@@ -555,7 +884,7 @@ fn executes_shift_right_instruction_without_carry() {
     vm.registers[0x2] = 0x7D; // Vx
     vm.registers[0x1] = 0xFE; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x7F, vm.registers[0x2]);
     assert_eq!(0x0, vm.registers[0xF]);
@@ -571,7 +900,7 @@ fn executes_shift_left_instruction_with_carry() {
     vm.registers[0x2] = 0x7D; // Vx
     vm.registers[0x1] = 0xFF; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xFE, vm.registers[0x2]);
     assert_eq!(0x1, vm.registers[0xF]);
@@ -587,7 +916,7 @@ fn executes_shift_left_instruction_without_carry() {
     vm.registers[0x2] = 0x7D; // Vx
     vm.registers[0x1] = 0x7F; // Vy
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xFE, vm.registers[0x2]);
     assert_eq!(0x0, vm.registers[0xF]);
@@ -602,7 +931,7 @@ fn executes_set_i_instruction() {
 
     vm.i = 0x007D;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x021E, vm.i);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -616,7 +945,7 @@ fn executes_jump_plus_instruction() {
 
     vm.registers[0x0] = 0x1E;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     let expected = 0x0ABC + 0x001E;
 
@@ -631,12 +960,50 @@ fn executes_random_mask_instruction() {
 
     vm.registers[0xA] = 0x1E;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert!(vm.registers[0xA] != 0x1E);
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
 
+#[test]
+fn executes_random_mask_instruction_with_a_seed() {
+    let random = |seed| {
+        let mut vm = VM::boot();
+        vm.set_rng_seed(seed);
+
+        (0..16)
+            .map(|_| {
+                vm.exec(Instruction::decode(0xCAFF).unwrap()).unwrap();
+                vm.registers[0xA]
+            })
+            .collect::<Vec<u8>>()
+    };
+
+    assert_eq!(random(1234), random(1234));
+    assert!(random(1234) != random(4321));
+}
+
+#[test]
+fn executes_random_mask_instruction_with_a_random_source() {
+    struct Fixed;
+
+    impl RandomSource for Fixed {
+        fn next_byte(&mut self) -> u8 {
+            0xAB
+        }
+    }
+
+    let instruction = Instruction::decode(0xCA0F).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_random_source(Box::new(Fixed));
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0x0B, vm.registers[0xA]);
+}
+
 #[test]
 fn executes_skip_on_key_pressed_instruction_when_key_is_pressed() {
     let instruction = Instruction::decode(0xEA9E).unwrap();
@@ -644,9 +1011,9 @@ fn executes_skip_on_key_pressed_instruction_when_key_is_pressed() {
     let mut vm = VM::boot();
 
     vm.registers[0xA] = 0xF; // We look for key F
-    vm.set_key(Key::F);      // key F is pressed
+    vm.key_down(Key::F);     // key F is pressed
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 4, vm.pc);
 }
@@ -659,7 +1026,7 @@ fn executes_skip_on_key_pressed_instruction_when_key_is_not_pressed() {
 
     vm.registers[0xA] = 0xF; // We look for key F
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
@@ -671,9 +1038,9 @@ fn executes_skip_on_key_not_pressed_instruction_when_key_is_pressed() {
     let mut vm = VM::boot();
 
     vm.registers[0xA] = 0xF; // We look for key F
-    vm.set_key(Key::F);      // key F is pressed
+    vm.key_down(Key::F);     // key F is pressed
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 2, vm.pc);
 }
@@ -686,7 +1053,7 @@ fn executes_skip_on_key_not_pressed_instruction_when_key_is_not_pressed() {
 
     vm.registers[0xA] = 0xF; // We look for key F
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(PROGRAM_START + 4, vm.pc);
 }
@@ -699,7 +1066,7 @@ fn executes_store_delay_timer_instruction() {
 
     vm.dt = 0xE;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xE, vm.registers[0xA]);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -713,7 +1080,7 @@ fn executes_set_delay_timer_instruction() {
 
     vm.registers[0xA] = 0xE;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xE, vm.dt);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -727,7 +1094,7 @@ fn executes_set_sound_timer_instruction() {
 
     vm.registers[0xA] = 0xE;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0xE, vm.st);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -739,7 +1106,7 @@ fn executes_wait_key_instruction_without_any_key_pressed() {
 
     let mut vm = VM::boot();
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x0, vm.registers[0xA]);
     assert_eq!(PROGRAM_START, vm.pc); // It doesn't move
@@ -751,14 +1118,47 @@ fn executes_wait_key_instruction_with_a_key_pressed() {
 
     let mut vm = VM::boot();
 
-    vm.set_key(Key::B);
+    vm.key_down(Key::B);
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0x0, vm.registers[0xA]);
+    assert_eq!(PROGRAM_START, vm.pc); // It waits for the release
 
-    vm.exec(instruction);
+    vm.key_up(Key::B);
+    vm.exec(Instruction::decode(0xFA0A).unwrap()).unwrap();
 
     assert_eq!(0xB, vm.registers[0xA]);
     assert_eq!(PROGRAM_START + 2, vm.pc); // It moves
 }
 
+#[test]
+fn executes_wait_key_instruction_while_the_key_is_held() {
+    let mut vm = VM::boot();
+
+    vm.key_down(Key::B);
+    for _ in 0..3 {
+        vm.exec(Instruction::decode(0xFA0A).unwrap()).unwrap();
+    }
+
+    assert_eq!(PROGRAM_START, vm.pc);
+}
+
+#[test]
+fn keeps_the_key_when_held_for_more_than_one_instruction() {
+    let instruction = Instruction::decode(0xEA9E).unwrap();
+
+    let mut vm = VM::boot();
+
+    vm.registers[0xA] = 0xF;
+    vm.key_down(Key::F);
+
+    vm.exec(instruction).unwrap();
+    vm.pc = PROGRAM_START;
+    vm.exec(Instruction::decode(0xEA9E).unwrap()).unwrap();
+
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+}
+
 #[test]
 fn executes_add_i_instruction() {
     let instruction = Instruction::decode(0xFA1E).unwrap();
@@ -768,7 +1168,7 @@ fn executes_add_i_instruction() {
     vm.i = 0x1;
     vm.registers[0xA] = 0x1;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(0x2, vm.i);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -784,7 +1184,7 @@ fn executes_set_sprite_instruction() {
 
     let expected = SPRITES_ADDR + 0xA * SPRITE_HEIGHT;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(expected, vm.i);
     assert_eq!(PROGRAM_START + 2, vm.pc);
@@ -799,7 +1199,7 @@ fn executes_bcd_instruction() {
     vm.i = 0x0FF0;
     vm.registers[0xA] = 254;
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     assert_eq!(2, vm.ram[0xFF0]);
     assert_eq!(5, vm.ram[0xFF1]);
@@ -819,7 +1219,7 @@ fn executes_store_instruction() {
         vm.registers[i] = 0xA;
     }
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     for i in 0x0..0xE {
         let index = (0x0F00 + i) as usize;
@@ -841,7 +1241,7 @@ fn executes_read_instruction() {
         vm.ram[0x0F00 + i] = 0xA;
     }
 
-    vm.exec(instruction);
+    vm.exec(instruction).unwrap();
 
     for i in 0x0..0xE {
         assert_eq!(0xA, vm.registers[i as usize]);
@@ -849,3 +1249,1352 @@ fn executes_read_instruction() {
     assert_eq!(PROGRAM_START + 2, vm.pc);
     assert_eq!(0x0F0F, vm.i);
 }
+
+#[test]
+fn executes_shift_right_instruction_in_place_with_quirk() {
+    let instruction = Instruction::decode(0x8216).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_quirks(quirks::CHIP_48);
+
+    vm.registers[0x2] = 0x7D; // Vx
+    vm.registers[0x1] = 0xFE; // Vy
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0x3E, vm.registers[0x2]);
+    assert_eq!(0x1, vm.registers[0xF]);
+}
+
+#[test]
+fn executes_shift_left_instruction_in_place_with_quirk() {
+    let instruction = Instruction::decode(0x821E).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_quirks(quirks::CHIP_48);
+
+    vm.registers[0x2] = 0x7D; // Vx
+    vm.registers[0x1] = 0xFF; // Vy
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0xFA, vm.registers[0x2]);
+    assert_eq!(0x0, vm.registers[0xF]);
+}
+
+#[test]
+fn executes_jump_plus_instruction_with_vx_quirk() {
+    let instruction = Instruction::decode(0xBABC).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_quirks(quirks::SUPER_CHIP);
+
+    vm.registers[0x0] = 0x1E;
+    vm.registers[0xA] = 0x02;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0x0ABC + 0x0002, vm.pc);
+}
+
+#[test]
+fn executes_logic_instructions_with_vf_reset_quirk() {
+    let mut vm = VM::boot();
+    vm.set_quirks(quirks::COSMAC_VIP);
+
+    for bytes in [0x8121, 0x8122, 0x8123].iter() {
+        vm.registers[0xF] = 0x1;
+
+        vm.exec(Instruction::decode(*bytes).unwrap()).unwrap();
+
+        assert_eq!(0x0, vm.registers[0xF]);
+    }
+}
+
+#[test]
+fn executes_store_instruction_with_memory_increment_quirks() {
+    let instruction = Instruction::decode(0xF355).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_quirks(quirks::CHIP_48);
+    vm.i = 0x0F00;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0x0F03, vm.i);
+
+    let instruction = Instruction::decode(0xF365).unwrap();
+
+    vm.set_quirks(quirks::SUPER_CHIP);
+    vm.i = 0x0F00;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0x0F00, vm.i);
+}
+
+#[test]
+fn executes_store_instruction_including_vx() {
+    let instruction = Instruction::decode(0xF255).unwrap();
+
+    let mut vm = VM::boot();
+    vm.i = 0x0F00;
+    vm.registers[0x2] = 0xA;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0xA, vm.ram[0x0F02]);
+}
+
+#[test]
+fn executes_draw_instruction_wrapping_around_the_edges() {
+    let instruction = Instruction::decode(0xD011).unwrap();
+
+    let mut vm = VM::boot();
+    vm.i = 0x0F00;
+    vm.ram[0x0F00] = 0xFF;
+    vm.registers[0x0] = (DISPLAY_WIDTH - 4) as u8;
+    vm.registers[0x1] = 0;
+
+    vm.exec(instruction).unwrap();
+
+    assert!(vm.gfx[DISPLAY_WIDTH - 4..DISPLAY_WIDTH].iter().all(|&p| p == 1));
+    assert!(vm.gfx[0..4].iter().all(|&p| p == 1));
+}
+
+#[test]
+fn executes_draw_instruction_clipping_the_edges_with_quirk() {
+    let instruction = Instruction::decode(0xD012).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_quirks(quirks::COSMAC_VIP);
+    vm.i = 0x0F00;
+    vm.ram[0x0F00] = 0xFF;
+    vm.ram[0x0F01] = 0xFF;
+    vm.registers[0x0] = (DISPLAY_WIDTH - 4) as u8;
+    vm.registers[0x1] = (DISPLAY_HEIGHT - 1) as u8;
+
+    vm.exec(instruction).unwrap();
+
+    let last_line = (DISPLAY_HEIGHT - 1) * DISPLAY_WIDTH;
+    assert!(vm.gfx[last_line + DISPLAY_WIDTH - 4..].iter().all(|&p| p == 1));
+    assert_eq!(4, vm.gfx.iter().filter(|&&p| p == 1).count());
+}
+
+#[test]
+fn waits_for_the_next_frame_after_drawing_with_display_wait_quirk() {
+    // DRW V0, V0, 1 followed by ADD V1, 1
+    let rom: Vec<u8> = vec![0xD0, 0x01, 0x71, 0x01, 0x71, 0x01, 0x71, 0x01];
+    let mut reader = Cursor::new(rom);
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut reader).unwrap();
+    vm.set_quirks(quirks::COSMAC_VIP);
+    vm.set_instructions_per_frame(3);
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+
+    vm.cycle().unwrap();
+
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+    assert_eq!(0, vm.registers[0x1]);
+
+    clock.advance(1);
+
+    vm.cycle().unwrap();
+
+    assert_eq!(PROGRAM_START + 8, vm.pc);
+    assert_eq!(3, vm.registers[0x1]);
+}
+
+#[test]
+fn executes_draw_instruction_with_a_big_sprite_in_high_res() {
+    let instruction = Instruction::decode(0xD010).unwrap();
+
+    let mut vm = VM::boot();
+    vm.exec(Instruction::decode(0x00FF).unwrap()).unwrap();
+
+    vm.i = 0x0F00;
+    for addr in 0x0F00..0x0F20 {
+        vm.ram[addr] = 0xFF;
+    }
+    vm.registers[0x0] = 100;
+    vm.registers[0x1] = 40;
+
+    vm.exec(instruction).unwrap();
+
+    for y in 40..56 {
+        let line = y * HIRES_DISPLAY_WIDTH;
+        assert!(vm.gfx[line + 100..line + 116].iter().all(|&p| p == 1));
+    }
+    assert_eq!(256, vm.gfx.iter().filter(|&&p| p == 1).count());
+    assert_eq!(0x0, vm.registers[0xF]);
+
+    vm.exec(Instruction::decode(0xD010).unwrap()).unwrap();
+
+    assert!(vm.gfx.iter().all(|&p| p == 0));
+    assert_eq!(0x1, vm.registers[0xF]);
+}
+
+#[test]
+fn executes_set_big_sprite_instruction() {
+    let instruction = Instruction::decode(0xFA30).unwrap();
+
+    let mut vm = VM::boot();
+
+    vm.registers[0xA] = 0x9;
+
+    let expected = BIG_SPRITES_ADDR + 0x9 * BIG_SPRITE_HEIGHT;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(expected, vm.i);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_store_and_read_flags_instructions() {
+    let mut vm = VM::boot();
+
+    for v in 0x0..0x8 {
+        vm.registers[v] = v as u8 + 1;
+    }
+
+    vm.exec(Instruction::decode(0xF775).unwrap()).unwrap();
+
+    assert_eq!([1, 2, 3, 4, 5, 6, 7, 8], vm.flags[0..8]);
+
+    vm.registers = [0; GENERAL_REGISTERS_SIZE];
+
+    vm.exec(Instruction::decode(0xF385).unwrap()).unwrap();
+
+    assert_eq!([1, 2, 3, 4, 0, 0, 0, 0], vm.registers[0..8]);
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+}
+
+#[test]
+fn resizes_the_ram() {
+    let mut vm = VM::boot();
+    vm.set_ram_size(XO_RAM_SIZE);
+
+    assert_eq!(XO_RAM_SIZE, vm.ram.len());
+}
+
+#[test]
+fn executes_store_range_instruction() {
+    let instruction = Instruction::decode(0x5242).unwrap();
+
+    let mut vm = VM::boot();
+
+    vm.i = 0x0F00;
+    vm.registers[0x2] = 0xA;
+    vm.registers[0x3] = 0xB;
+    vm.registers[0x4] = 0xC;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!([0xA, 0xB, 0xC], vm.ram[0x0F00..0x0F03]);
+    assert_eq!(0x0F00, vm.i);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_read_range_instruction_in_reverse_order() {
+    let instruction = Instruction::decode(0x5423).unwrap();
+
+    let mut vm = VM::boot();
+
+    vm.i = 0x0F00;
+    vm.ram[0x0F00] = 0xA;
+    vm.ram[0x0F01] = 0xB;
+    vm.ram[0x0F02] = 0xC;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!([0xC, 0xB, 0xA], vm.registers[0x2..0x5]);
+    assert_eq!(0x0F00, vm.i);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_long_set_i_instruction() {
+    let instruction = Instruction::decode(0xF000).unwrap();
+
+    let mut vm = VM::boot();
+    vm.set_ram_size(XO_RAM_SIZE);
+
+    vm.ram[PROGRAM_START + 2] = 0xAB;
+    vm.ram[PROGRAM_START + 3] = 0xCD;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(0xABCD, vm.i);
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+}
+
+#[test]
+fn skips_over_long_set_i_instructions() {
+    let instruction = Instruction::decode(0x3000).unwrap();
+
+    let mut vm = VM::boot();
+
+    vm.ram[PROGRAM_START + 2] = 0xF0;
+    vm.ram[PROGRAM_START + 3] = 0x00;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(PROGRAM_START + 6, vm.pc);
+}
+
+#[test]
+fn executes_draw_instruction_on_both_planes() {
+    let mut vm = VM::boot();
+
+    vm.exec(Instruction::decode(0xF301).unwrap()).unwrap();
+
+    vm.i = 0x0F00;
+    vm.ram[0x0F00] = 0b10000000; // Plane 1
+    vm.ram[0x0F01] = 0b11000000; // Plane 2
+
+    vm.exec(Instruction::decode(0xD001).unwrap()).unwrap();
+
+    assert_eq!(0b11, vm.gfx[0]);
+    assert_eq!(0b10, vm.gfx[1]);
+    assert_eq!(0x0, vm.registers[0xF]);
+
+    // Only the second plane collides
+    vm.exec(Instruction::decode(0xF201).unwrap()).unwrap();
+    vm.i = 0x0F01;
+
+    vm.exec(Instruction::decode(0xD001).unwrap()).unwrap();
+
+    assert_eq!(0b01, vm.gfx[0]);
+    assert_eq!(0b00, vm.gfx[1]);
+    assert_eq!(0x1, vm.registers[0xF]);
+}
+
+#[test]
+fn executes_clear_instruction_on_the_selected_plane() {
+    let mut vm = VM::boot();
+
+    vm.gfx = vec![0b11; DISPLAY_PIXELS];
+
+    vm.exec(Instruction::decode(0xF201).unwrap()).unwrap();
+    vm.exec(Instruction::decode(0x00E0).unwrap()).unwrap();
+
+    assert!(vm.gfx.iter().all(|&p| p == 0b01));
+}
+
+#[test]
+fn executes_scroll_up_instruction() {
+    let instruction = Instruction::decode(0x00D2).unwrap();
+    let mut vm = VM::boot();
+
+    vm.gfx[2 * DISPLAY_WIDTH + 3] = 1;
+    vm.gfx[DISPLAY_WIDTH] = 1; // Falls off the screen
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(1, vm.gfx[3]);
+    assert_eq!(1, vm.gfx.iter().filter(|&&p| p == 1).count());
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_load_audio_instruction() {
+    let instruction = Instruction::decode(0xF002).unwrap();
+
+    let mut vm = VM::boot();
+
+    vm.i = 0x0F00;
+    for addr in 0x0F00..0x0F10 {
+        vm.ram[addr] = 0xAA;
+    }
+
+    vm.exec(instruction).unwrap();
+
+    assert!(vm.audio_pattern().iter().all(|&b| b == 0xAA));
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn executes_set_pitch_instruction() {
+    let instruction = Instruction::decode(0xFA3A).unwrap();
+
+    let mut vm = VM::boot();
+
+    assert_eq!(4000.0, vm.playback_rate());
+
+    vm.registers[0xA] = 112;
+
+    vm.exec(instruction).unwrap();
+
+    assert_eq!(112, vm.pitch);
+    assert_eq!(8000.0, vm.playback_rate());
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn fails_to_execute_memory_instructions_outside_of_the_ram() {
+    let mut vm = VM::boot();
+
+    for &bytes in [0xD015, 0xF033, 0xF355, 0xF365, 0x5032, 0xF002].iter() {
+        vm.i = RAM_SIZE - 2;
+
+        match vm.exec(Instruction::decode(bytes).unwrap()) {
+            Err(VmError::InvalidAddress { pc, opcode, address }) => {
+                assert_eq!(PROGRAM_START, pc);
+                assert_eq!(bytes, opcode);
+                assert_eq!(RAM_SIZE, address);
+            }
+            other => panic!("Unexpected result {:?} for {:#06X}", other, bytes),
+        }
+    }
+    assert_eq!(PROGRAM_START, vm.pc);
+}
+
+#[test]
+fn fails_to_present_when_the_display_is_disconnected() {
+    let mut vm = VM::boot();
+    // DRW V0, V1, 1; JP 0x202
+    vm.load_rom(&mut Cursor::new(vec![0xD0, 0x11, 0x12, 0x02])).unwrap();
+    let (sender, receiver) = display::latest_frame();
+    vm.set_display(Box::new(sender));
+    drop(receiver);
+
+    match vm.run_for(1) {
+        Err(VmError::DisplayDisconnected) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn saves_and_loads_the_vm_state() {
+    let mut vm = VM::boot();
+    vm.set_rng_seed(42);
+    vm.load_sprites();
+    vm.ram[PROGRAM_START] = 0xAB;
+    vm.registers[0x3] = 0x42;
+    vm.stack[0] = 0x0204;
+    vm.sp = 1;
+    vm.pc = 0x0208;
+    vm.i = 0x0300;
+    vm.dt = 10;
+    vm.st = 5;
+    vm.keypad[0xA] = true;
+    vm.gfx[10] = 1;
+
+    let mut state = Vec::new();
+    vm.save_state(&mut state).unwrap();
+    let expected: Vec<u8> = (0..8).map(|_| vm.rng.next_byte()).collect();
+
+    let mut restored = VM::boot();
+    restored.load_state(&mut Cursor::new(state)).unwrap();
+
+    assert_eq!(vm.ram.as_slice(), restored.ram.as_slice());
+    assert_eq!(vm.registers, restored.registers);
+    assert_eq!(vm.stack, restored.stack);
+    assert_eq!(1, restored.sp);
+    assert_eq!(0x0208, restored.pc);
+    assert_eq!(0x0300, restored.i);
+    assert_eq!(10, restored.dt);
+    assert_eq!(5, restored.st);
+    assert_eq!(vm.keypad, restored.keypad);
+    assert_eq!(vm.gfx, restored.gfx);
+
+    let actual: Vec<u8> = (0..8).map(|_| restored.rng.next_byte()).collect();
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn loads_the_resolution_and_refreshes_the_display() {
+    let mut vm = VM::boot();
+    vm.exec(Instruction::decode(0x00FF).unwrap()).unwrap();
+    vm.gfx[HIRES_DISPLAY_PIXELS - 1] = 1;
+
+    let mut state = Vec::new();
+    vm.save_state(&mut state).unwrap();
+
+    let mut restored = VM::boot();
+    let (sender, receiver) = display::latest_frame();
+    restored.set_display(Box::new(sender));
+    restored.load_state(&mut Cursor::new(state)).unwrap();
+
+    assert_eq!((HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT), restored.resolution());
+    let frame = receiver.take().unwrap();
+    assert_eq!((HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT), (frame.width, frame.height));
+    assert_eq!(Rect::new(0, 0, HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT), frame.dirty);
+    assert_eq!(Rgb::new(255, 255, 255),
+               display::color(frame.pixels[HIRES_DISPLAY_PIXELS - 1]));
+}
+
+#[test]
+fn fails_to_load_a_state_from_another_version() {
+    let mut state = Vec::new();
+    VM::boot().save_state(&mut state).unwrap();
+    state[4] = 99;
+
+    match VM::boot().load_state(&mut Cursor::new(state)) {
+        Err(VmError::SaveStateVersion { version: 99 }) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn fails_to_load_an_invalid_state() {
+    match VM::boot().load_state(&mut Cursor::new(vec![0x00, 0xE0, 0x12, 0x00])) {
+        Err(VmError::InvalidSaveState(_)) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    let mut state = Vec::new();
+    VM::boot().save_state(&mut state).unwrap();
+    state.truncate(100);
+    match VM::boot().load_state(&mut Cursor::new(state)) {
+        Err(VmError::Io(_)) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn fails_to_load_a_state_with_unknown_bitplanes() {
+    let mut vm = VM::boot();
+    let mut state = Vec::new();
+    vm.save_state(&mut state).unwrap();
+    vm.planes = 0b10;
+    let mut changed = Vec::new();
+    vm.save_state(&mut changed).unwrap();
+
+    // The planes are the only byte that differs
+    let at = state.iter().zip(&changed).position(|(a, b)| a != b).unwrap();
+    changed[at] = 0b100;
+    match VM::boot().load_state(&mut Cursor::new(changed)) {
+        Err(VmError::InvalidSaveState(_)) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn fails_to_load_a_state_with_another_ram_size() {
+    let mut vm = VM::boot();
+    vm.set_ram_size(XO_RAM_SIZE);
+
+    let mut state = Vec::new();
+    vm.save_state(&mut state).unwrap();
+
+    match VM::boot().load_state(&mut Cursor::new(state)) {
+        Err(VmError::InvalidSaveState(_)) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn rewinds_to_previous_frames() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(1).set_rewind_frames(10);
+    // ADD V0, 1 in a loop
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    for _ in 0..6 {
+        clock.advance(1);
+    }
+    vm.cycle().unwrap();
+    assert_eq!(3, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START, vm.pc);
+
+    assert_eq!(2, vm.rewind(2).unwrap());
+    assert_eq!(2, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START, vm.pc);
+
+    // It can't go further back than the oldest frame
+    assert_eq!(3, vm.rewind(10).unwrap());
+    assert_eq!(1, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+    assert_eq!(0, vm.rewind(1).unwrap());
+}
+
+#[test]
+fn rewinding_keeps_the_keys_held_now() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(1).set_rewind_frames(30);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    vm.key_down(Key::Num5);
+    vm.run_for(20).unwrap();
+    vm.key_up(Key::Num5);
+    vm.key_down(Key::Num7);
+    vm.run_for(5).unwrap();
+
+    assert_eq!(15, vm.rewind(15).unwrap());
+    assert!(!vm.is_key_down(Key::Num5));
+    assert!(vm.is_key_down(Key::Num7));
+}
+
+#[test]
+fn keeps_only_the_configured_number_of_frames() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(1).set_rewind_frames(3);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    for _ in 0..10 {
+        clock.advance(1);
+    }
+    vm.cycle().unwrap();
+
+    assert_eq!(2, vm.rewind(100).unwrap());
+    assert_eq!(4, vm.registers[0x0]);
+}
+
+#[test]
+fn rewinds_a_frame_per_tick_while_rewinding() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(2).set_rewind_frames(10);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    for _ in 0..4 {
+        clock.advance(1);
+    }
+    vm.cycle().unwrap();
+    assert_eq!(4, vm.registers[0x0]);
+
+    vm.set_rewinding(true);
+    for _ in 0..2 {
+        clock.advance(1);
+    }
+    vm.cycle().unwrap();
+    assert_eq!(2, vm.registers[0x0]);
+
+    vm.set_rewinding(false);
+    clock.advance(1);
+    vm.cycle().unwrap();
+    assert_eq!(3, vm.registers[0x0]);
+}
+
+#[test]
+fn does_not_rewind_by_default() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(1);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    let clock = ManualClock::new();
+    vm.set_clock(Box::new(clock.clone()));
+    clock.advance(1);
+    clock.advance(1);
+    vm.cycle().unwrap();
+
+    assert_eq!(0, vm.rewind(1).unwrap());
+    assert_eq!(1, vm.registers[0x0]);
+}
+
+#[test]
+fn steps_one_instruction_at_a_time() {
+    let mut vm = VM::boot();
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x70, 0x01])).unwrap();
+    vm.dt = 5;
+
+    vm.step().unwrap();
+    assert_eq!(1, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+    assert_eq!(5, vm.dt);
+
+    vm.step().unwrap();
+    assert_eq!(2, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+}
+
+#[test]
+fn steps_a_whole_frame_without_a_clock() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(3);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+    vm.dt = 5;
+    vm.st = 5;
+
+    vm.step_frame().unwrap();
+
+    assert_eq!(2, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+    assert_eq!(4, vm.dt);
+    assert_eq!(4, vm.st);
+}
+
+#[test]
+fn runs_for_a_number_of_frames() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(2);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+    vm.dt = 20;
+
+    vm.run_for(10).unwrap();
+
+    assert_eq!(10, vm.registers[0x0]);
+    assert_eq!(10, vm.dt);
+}
+
+#[test]
+fn stops_running_when_the_program_exits() {
+    let mut vm = VM::boot();
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x00, 0xFD])).unwrap();
+
+    vm.run_for(10).unwrap();
+    vm.step().unwrap();
+
+    assert_eq!(State::Exited, vm.state());
+    assert_eq!(1, vm.registers[0x0]);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+}
+
+#[test]
+fn sends_memory_accesses_through_the_bus() {
+    let mut vm = VM::boot();
+    // LD I, 0x300; LD V0, 123; LD B, V0
+    vm.load_rom(&mut Cursor::new(vec![0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33])).unwrap();
+    vm.memory_mut().watch(0x300..0x303, Access::Write);
+    vm.memory_mut().count_accesses(true);
+
+    vm.step().unwrap();
+    vm.step().unwrap();
+    vm.step().unwrap();
+
+    let hits = vm.memory_mut().take_hits();
+    let written: Vec<(usize, u8)> = hits.iter().map(|hit| (hit.address, hit.value)).collect();
+    assert_eq!(vec![(0x300, 1), (0x301, 2), (0x302, 3)], written);
+    assert_eq!(1, vm.memory().reads(PROGRAM_START));
+    assert_eq!(1, vm.memory().writes(0x301));
+}
+
+#[test]
+fn charges_vip_cycles_per_instruction() {
+    let mut vm = VM::boot();
+    vm.set_timing(Timing::Vip);
+    // ADD V0, 1 in a loop
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    vm.step_frame().unwrap();
+
+    // ADD takes 50 cycles and JP 52, so a frame runs 50 of each
+    let budget = VIP_CYCLES_PER_FRAME - VIP_DISPLAY_CYCLES;
+    assert_eq!(((budget + 101) / 102) as u8, vm.registers[0x0]);
+}
+
+#[test]
+fn carries_the_cycles_of_slow_instructions_to_the_next_frame() {
+    let mut vm = VM::boot();
+    vm.set_timing(Timing::Vip);
+    // CLS; CLS; ADD V0, 1
+    vm.load_rom(&mut Cursor::new(vec![0x00, 0xE0, 0x00, 0xE0, 0x70, 0x01])).unwrap();
+
+    vm.step_frame().unwrap();
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+
+    // The second CLS still overruns the budget of the next frame
+    vm.step_frame().unwrap();
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+    assert!(vm.cycle_budget <= 0);
+}
+
+#[test]
+fn ends_the_vip_frame_after_a_draw_with_display_wait() {
+    let mut vm = VM::boot();
+    vm.set_timing(Timing::Vip).set_quirks(quirks::COSMAC_VIP);
+    // DRW V0, V0, 1; ADD V1, 1
+    vm.load_rom(&mut Cursor::new(vec![0xD0, 0x01, 0x71, 0x01])).unwrap();
+
+    vm.step_frame().unwrap();
+
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+    assert_eq!(0, vm.registers[0x1]);
+}
+
+#[test]
+fn caches_decoded_instructions() {
+    let mut vm = VM::boot();
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01])).unwrap();
+
+    vm.step().unwrap();
+
+    let cache = vm.decode_cache.as_ref().unwrap();
+    assert_eq!(Some(Instruction::decode(0x7001).unwrap()), cache[PROGRAM_START]);
+}
+
+#[test]
+fn invalidates_decoded_instructions_written_by_the_program() {
+    let rom: Vec<u8> = vec![
+        0x72, 0x01, // ADD V2, 1
+        0x60, 0x71, // LD V0, 0x71
+        0x61, 0x05, // LD V1, 0x05
+        0xA2, 0x00, // LD I, 0x200
+        0xF1, 0x55, // LD [I], V1; turns the first instruction into ADD V1, 5
+        0x12, 0x00, // JP 0x200
+    ];
+
+    let mut vm = VM::boot();
+    vm.load_rom(&mut Cursor::new(rom)).unwrap();
+    for _ in 0..7 {
+        vm.step().unwrap();
+    }
+
+    assert_eq!(1, vm.registers[0x2]);
+    assert_eq!(0x0A, vm.registers[0x1]);
+}
+
+#[test]
+fn runs_without_the_decode_cache() {
+    let mut vm = VM::boot();
+    vm.set_decode_cache(false);
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    vm.run_for(2).unwrap();
+
+    assert!(vm.decode_cache.is_none());
+    assert_eq!(10, vm.registers[0x0]);
+}
+
+#[test]
+fn builds_a_vm_ready_to_run() {
+    let clock = ManualClock::new();
+    let mut vm = VmBuilder::new()
+        .cpu_frequency(120)
+        .seed(7)
+        .clock(Box::new(clock.clone()))
+        .rom(vec![0x70, 0x01, 0x12, 0x00])
+        .build()
+        .unwrap();
+
+    assert_eq!([0xF0, 0x90, 0x90, 0x90, 0xF0], vm.ram[0..5]);
+    assert_eq!(BIG_SPRITES[..], vm.ram[BIG_SPRITES_ADDR..BIG_SPRITES_ADDR + BIG_SPRITES_SIZE]);
+    assert_eq!(0x70, vm.ram[PROGRAM_START]);
+
+    clock.advance(1);
+    vm.cycle().unwrap();
+
+    assert_eq!(1, vm.registers[0x0]);
+}
+
+#[test]
+fn builds_a_vm_from_a_config() {
+    let config = VmConfig {
+        ram_size: XO_RAM_SIZE,
+        program_start: 0x600,
+        fonts: Fonts::Chip8,
+        stack_depth: 4,
+        quirks: quirks::XO_CHIP,
+        ..VmConfig::default()
+    };
+
+    let vm = VmBuilder::from_config(config).rom(vec![0x00, 0xE0]).build().unwrap();
+
+    assert_eq!(XO_RAM_SIZE, vm.ram.len());
+    assert_eq!(0x600, vm.pc);
+    assert_eq!(0xE0, vm.ram[0x601]);
+    assert_eq!(0, vm.ram[BIG_SPRITES_ADDR]);
+    assert_eq!(4, vm.stack.len());
+    assert_eq!(quirks::XO_CHIP, vm.quirks);
+}
+
+#[test]
+fn the_stack_depth_fits_in_a_byte() {
+    let mut vm = VM::boot();
+
+    assert!(vm.set_stack_depth(255).is_ok());
+    match vm.set_stack_depth(256) {
+        Err(VmError::InvalidConfig(_)) => (),
+        other => panic!("Unexpected result {:?}", other.map(|_| ())),
+    }
+    assert_eq!(255, vm.stack.len());
+}
+
+#[test]
+fn fails_to_build_with_mistakes_in_the_config() {
+    let builds = vec![
+        VmBuilder::new(),
+        VmBuilder::new().rom(vec![]).ram_size(XO_RAM_SIZE + 1),
+        VmBuilder::new().rom(vec![]).program_start(RAM_SIZE),
+        VmBuilder::new().rom(vec![]).program_start(0x201),
+        VmBuilder::new().rom(vec![]).program_start(0x40),
+        VmBuilder::new().rom(vec![]).stack_depth(0),
+        VmBuilder::new().rom(vec![]).cpu_frequency(0),
+    ];
+
+    for builder in builds {
+        match builder.build() {
+            Err(VmError::InvalidConfig(_)) => (),
+            Err(other) => panic!("Unexpected error {:?}", other),
+            Ok(_) => panic!("The VM was built"),
+        }
+    }
+
+    match VmBuilder::new().rom(vec![0; RAM_SIZE]).build() {
+        Err(VmError::RomTooLarge { .. }) => (),
+        Err(other) => panic!("Unexpected error {:?}", other),
+        Ok(_) => panic!("The VM was built"),
+    }
+}
+
+#[test]
+fn starts_and_stops_the_audio_sink() {
+    struct Sink(Arc<Mutex<Vec<&'static str>>>);
+
+    impl AudioSink for Sink {
+        fn start(&mut self, _pattern: &[u8], _rate: f64) {
+            self.0.lock().unwrap().push("start");
+        }
+
+        fn stop(&mut self) {
+            self.0.lock().unwrap().push("stop");
+        }
+    }
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.set_audio_sink(Box::new(Sink(events.clone())));
+    // LD V0, 2; LD ST, V0; ADD V1, 1; JP 0x204
+    vm.load_rom(&mut Cursor::new(vec![0x60, 0x02, 0xF0, 0x18, 0x71, 0x01, 0x12, 0x04])).unwrap();
+
+    vm.run_for(1).unwrap();
+    assert_eq!(vec!["start"], *events.lock().unwrap());
+
+    vm.run_for(3).unwrap();
+    assert_eq!(vec!["start", "stop"], *events.lock().unwrap());
+}
+
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl VmObserver for Recorder {
+    fn on_instruction(&mut self, pc: usize, _instruction: &Instruction) {
+        self.0.lock().unwrap().push(format!("instruction {:#X}", pc));
+    }
+
+    fn on_draw(&mut self, x: usize, y: usize, rows: usize, collision: bool) {
+        self.0.lock().unwrap().push(format!("draw {} {} {} {}", x, y, rows, collision));
+    }
+
+    fn on_clear(&mut self) {
+        self.0.lock().unwrap().push("clear".to_string());
+    }
+
+    fn on_call(&mut self, pc: usize, address: usize) {
+        self.0.lock().unwrap().push(format!("call {:#X} {:#X}", pc, address));
+    }
+
+    fn on_return(&mut self, pc: usize, address: usize) {
+        self.0.lock().unwrap().push(format!("return {:#X} {:#X}", pc, address));
+    }
+
+    fn on_memory_write(&mut self, address: usize, value: u8) {
+        self.0.lock().unwrap().push(format!("write {:#X} {}", address, value));
+    }
+
+    fn on_key_wait(&mut self, x: usize) {
+        self.0.lock().unwrap().push(format!("key wait {}", x));
+    }
+
+    fn on_sound_start(&mut self) {
+        self.0.lock().unwrap().push("sound start".to_string());
+    }
+}
+
+#[test]
+fn notifies_the_observers() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.add_observer(Box::new(Recorder(events.clone())));
+    // CALL 0x206; JP 0x204; 00E0; DRW V0, V0, 1; FX33 with I = 0; RET
+    vm.load_rom(&mut Cursor::new(vec![0x22, 0x06, 0x12, 0x04, 0x00, 0x00,
+                                      0x00, 0xE0, 0xD0, 0x01, 0xF0, 0x33, 0x00, 0xEE]))
+        .unwrap();
+
+    for _ in 0..6 {
+        vm.step().unwrap();
+    }
+
+    let expected = vec!["instruction 0x200",
+                        "call 0x200 0x206",
+                        "instruction 0x206",
+                        "clear",
+                        "instruction 0x208",
+                        "draw 0 0 1 false",
+                        "instruction 0x20A",
+                        "write 0x0 0",
+                        "write 0x1 0",
+                        "write 0x2 0",
+                        "instruction 0x20C",
+                        "return 0x20C 0x202",
+                        "instruction 0x202"];
+    assert_eq!(expected, *events.lock().unwrap());
+}
+
+#[test]
+fn notifies_a_key_wait_once() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.add_observer(Box::new(Recorder(events.clone())));
+    // LD V1, K; LD V2, K
+    vm.load_rom(&mut Cursor::new(vec![0xF1, 0x0A, 0xF2, 0x0A])).unwrap();
+
+    for _ in 0..10 {
+        vm.step().unwrap();
+    }
+    vm.key_down(Key::Num5);
+    vm.step().unwrap();
+    vm.key_up(Key::Num5);
+    vm.step().unwrap();
+    vm.step().unwrap();
+
+    let events = events.lock().unwrap();
+    let waits: Vec<&String> = events.iter().filter(|e| e.starts_with("key wait")).collect();
+    assert_eq!(vec!["key wait 1", "key wait 2"], waits);
+}
+
+#[test]
+fn notifies_every_observer() {
+    let first = Arc::new(Mutex::new(vec![]));
+    let second = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.add_observer(Box::new(Recorder(first.clone())))
+        .add_observer(Box::new(Recorder(second.clone())));
+    // LD V0, 2; LD ST, V0; LD V1, K
+    vm.load_rom(&mut Cursor::new(vec![0x60, 0x02, 0xF0, 0x18, 0xF1, 0x0A])).unwrap();
+
+    vm.run_for(1).unwrap();
+
+    assert!(first.lock().unwrap().contains(&"sound start".to_string()));
+    assert!(first.lock().unwrap().contains(&"key wait 1".to_string()));
+    assert_eq!(*first.lock().unwrap(), *second.lock().unwrap());
+
+    vm.clear_observers();
+    vm.run_for(1).unwrap();
+    assert_eq!(*first.lock().unwrap(), *second.lock().unwrap());
+}
+
+#[test]
+fn treats_machine_calls_as_unknown_without_the_cdp1802() {
+    let mut vm = VM::boot();
+    vm.load_rom(&mut Cursor::new(vec![0x03, 0x00])).unwrap();
+
+    match vm.step() {
+        Err(VmError::UnknownOpcode { pc, opcode }) => {
+            assert_eq!(PROGRAM_START, pc);
+            assert_eq!(0x0300, opcode);
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn runs_machine_code_routines_on_the_cdp1802() {
+    let mut vm = VM::boot();
+    vm.set_machine_code(true);
+    // SYS 0x300; LD V1, 1
+    vm.load_rom(&mut Cursor::new(vec![0x03, 0x00, 0x61, 0x01])).unwrap();
+    vm.registers[0x3] = 0x21;
+    vm.i = 0x123;
+    // LDN R6 (VX, X is 3 in 0300); ADI 1; STR R6; LDI 0xFF; PLO RA (I);
+    // LDI 5; PLO R8 (sound timer); LDI 0x80; STR RB (display); SEP R4
+    let routine = vec![0x06, 0xFC, 0x01, 0x56, 0xF8, 0xFF, 0xAA, 0xF8, 0x05, 0xA8,
+                       0xF8, 0x80, 0x5B, 0xD4];
+    for (idx, byte) in routine.iter().enumerate() {
+        vm.ram[0x300 + idx] = *byte;
+    }
+
+    vm.step().unwrap();
+
+    assert_eq!(0x22, vm.registers[0x3]);
+    assert_eq!(0x1FF, vm.i);
+    assert_eq!(5, vm.st);
+    assert_eq!(PROGRAM_START + 2, vm.pc);
+    assert_eq!(1, vm.gfx[0]);
+    assert_eq!(0, vm.gfx[1]);
+
+    vm.step().unwrap();
+    assert_eq!(1, vm.registers[0x1]);
+}
+
+#[test]
+fn machine_code_routines_write_through_the_bus() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.set_machine_code(true);
+    vm.add_observer(Box::new(Recorder(events.clone())));
+    vm.load_rom(&mut Cursor::new(vec![0x03, 0x00])).unwrap();
+    vm.memory_mut().watch(0xEF0..0xF01, Access::Write);
+    vm.registers[0x3] = 0x21;
+    // LDN R6 (V3); ADI 1; STR R6; LDI 0x80; STR RB (display); SEP R4
+    let routine = [0x06, 0xFC, 0x01, 0x56, 0xF8, 0x80, 0x5B, 0xD4];
+    for (idx, byte) in routine.iter().enumerate() {
+        vm.ram[0x300 + idx] = *byte;
+    }
+
+    vm.step().unwrap();
+
+    let hits = vm.memory_mut().take_hits();
+    let written: Vec<(usize, u8)> = hits.iter().map(|hit| (hit.address, hit.value)).collect();
+    assert_eq!(vec![(0xEF3, 0x21), (0xEF3, 0x22), (0xF00, 0x80)], written);
+    let expected = vec!["instruction 0x200", "write 0xEF3 33", "write 0xEF3 34", "write 0xF00 128"];
+    assert_eq!(expected, *events.lock().unwrap());
+}
+
+#[test]
+fn fails_when_a_machine_code_routine_never_returns() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.set_machine_code(true);
+    vm.add_observer(Box::new(Recorder(events.clone())));
+    vm.load_rom(&mut Cursor::new(vec![0x03, 0x00])).unwrap();
+    // LDI 0x42; STR R6 (V3); BR 0x03
+    for (idx, byte) in [0xF8, 0x42, 0x56, 0x30, 0x03].iter().enumerate() {
+        vm.ram[0x300 + idx] = *byte;
+    }
+
+    match vm.step() {
+        Err(VmError::MachineCodeTimeout { pc, address }) => {
+            assert_eq!(PROGRAM_START, pc);
+            assert_eq!(0x300, address);
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    // What it did before the timeout is kept
+    assert!(events.lock().unwrap().contains(&"write 0xEF3 66".to_string()));
+    assert_eq!(0x42, vm.cdp1802.unwrap().d);
+    assert!(vm.decode_cache.as_ref().unwrap().is_empty());
+}
+
+#[test]
+fn halts_when_the_program_jumps_to_itself() {
+    let mut vm = VM::boot();
+    // LD V0, 3; LD ST, V0; JP 0x204
+    vm.load_rom(&mut Cursor::new(vec![0x60, 0x03, 0xF0, 0x18, 0x12, 0x04])).unwrap();
+
+    vm.run_for(1).unwrap();
+    assert_eq!(State::Halted, vm.state());
+    assert_eq!(PROGRAM_START + 4, vm.pc);
+
+    // Runs stop there, but the timers keep running
+    vm.run_for(2).unwrap();
+    assert_eq!(2, vm.st);
+    vm.step_frame().unwrap();
+    vm.step_frame().unwrap();
+    assert_eq!(0, vm.st);
+}
+
+#[test]
+fn idles_the_rest_of_the_frame_while_polling_the_delay_timer() {
+    let count = Arc::new(Mutex::new(vec![]));
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(1000);
+    vm.add_observer(Box::new(Recorder(count.clone())));
+    // LD V0, 2; LD DT, V0; LD V1, DT; SE V1, 0; JP 0x204; LD V2, 1; JP 0x20C
+    vm.load_rom(&mut Cursor::new(vec![0x60, 0x02, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00,
+                                      0x12, 0x04, 0x62, 0x01, 0x12, 0x0C]))
+        .unwrap();
+
+    vm.run_for(1).unwrap();
+    assert!(vm.idle);
+    assert!(count.lock().unwrap().len() < 20, "{} instructions", count.lock().unwrap().len());
+
+    vm.run_for(2).unwrap();
+    assert_eq!(1, vm.registers[0x2]);
+    assert_eq!(State::Halted, vm.state());
+}
+
+#[test]
+fn idles_while_waiting_for_a_key() {
+    let mut vm = VM::boot();
+    // LD V1, K; JP 0x200
+    vm.load_rom(&mut Cursor::new(vec![0xF1, 0x0A, 0x12, 0x00])).unwrap();
+
+    vm.run_for(1).unwrap();
+    assert!(vm.idle);
+
+    vm.key_down(Key::Num5);
+    vm.run_for(1).unwrap();
+    assert!(vm.idle); // Until the key is released
+
+    vm.key_up(Key::Num5);
+    vm.run_for(1).unwrap();
+    assert_eq!(5, vm.registers[0x1]);
+}
+
+#[test]
+fn does_not_idle_in_loops_that_make_progress() {
+    let mut vm = VM::boot();
+    vm.set_instructions_per_frame(100);
+    // ADD V0, 1; JP 0x200
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    vm.run_for(1).unwrap();
+
+    assert!(!vm.idle);
+    assert_eq!(50, vm.registers[0x0]);
+}
+
+#[test]
+fn builds_a_vm_with_the_eti_660_layout() {
+    let vm = VmBuilder::from_config(VmConfig::eti_660()).rom(vec![0x00, 0xE0]).build().unwrap();
+
+    assert_eq!(ETI_660_PROGRAM_START, vm.pc);
+    assert_eq!(0xE0, vm.ram[ETI_660_PROGRAM_START + 1]);
+    assert_eq!(0, vm.ram[PROGRAM_START + 1]);
+}
+
+#[test]
+fn starts_and_resets_at_the_entry_point() {
+    let mut vm = VmBuilder::new()
+        .entry_point(0x202)
+        .rom(vec![0x00, 0x00, 0x70, 0x01, 0x70, 0x01])
+        .build()
+        .unwrap();
+
+    assert_eq!(0x202, vm.pc);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert_eq!(2, vm.registers[0x0]);
+
+    vm.reset_pc();
+    assert_eq!(0x202, vm.pc);
+
+    match VmBuilder::new().entry_point(RAM_SIZE).rom(vec![]).build() {
+        Err(VmError::InvalidConfig(_)) => (),
+        other => panic!("Unexpected result {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn runs_hires_chip8_programs_on_a_64x64_display() {
+    let mut rom = vec![0x12, 0x60]; // JP 0x260, skips to 0x2C0
+    rom.resize(HIRES_CHIP8_START - PROGRAM_START, 0);
+    // LD V0, 40; LD V1, 0; LD F, V1; DRW V0, V0, 5
+    rom.extend_from_slice(&[0x60, 0x28, 0x61, 0x00, 0xF1, 0x29, 0xD0, 0x05]);
+    let mut vm = VmBuilder::from_config(VmConfig::hires_chip8()).rom(rom).build().unwrap();
+
+    assert_eq!((64, 64), vm.resolution());
+    vm.step().unwrap();
+    assert_eq!(HIRES_CHIP8_START, vm.pc);
+
+    for _ in 0..4 {
+        vm.step().unwrap();
+    }
+    // Below the 32 lines of the normal display, nothing wrapped
+    assert_eq!(1, vm.gfx[40 * 64 + 40]);
+    assert_eq!(0, vm.gfx[8 * 64 + 40]);
+
+    let mut state = vec![];
+    vm.save_state(&mut state).unwrap();
+    let mut copy = VmBuilder::from_config(VmConfig::hires_chip8()).rom(vec![]).build().unwrap();
+    copy.load_state(&mut Cursor::new(state)).unwrap();
+    assert_eq!(vm.gfx, copy.gfx);
+}
+
+#[test]
+fn presents_the_whole_screen_after_switching_to_hires_chip8() {
+    let mut vm = VM::boot();
+    // ADD V0, 1; JP 0x200
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+    let (sender, receiver) = display::latest_frame();
+    vm.set_display(Box::new(sender));
+
+    vm.set_hires_chip8(true);
+    vm.run_for(1).unwrap();
+
+    let frame = receiver.take().unwrap();
+    assert_eq!((64, 64), (frame.width, frame.height));
+    assert_eq!(Rect::new(0, 0, 64, 64), frame.dirty);
+}
+
+#[test]
+fn draws_on_any_display_sink() {
+    let screen = FramebufferSink::new();
+    let mut vm = VmBuilder::new()
+        .display(Box::new(screen.clone()))
+        .rom(vec![0x00, 0xFF, 0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05]) // HIGH; LD V0, 0; LD F, V0; DRW
+        .build()
+        .unwrap();
+
+    // The sink starts with the screen of the VM
+    assert_eq!(DISPLAY_WIDTH, screen.frame().width);
+
+    for _ in 0..4 {
+        vm.step().unwrap();
+    }
+
+    let frame = screen.frame();
+    assert_eq!((HIRES_DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT), (frame.width, frame.height));
+    assert_eq!(vm.gfx, frame.pixels);
+    assert_eq!(1, screen.pixel(0, 0));
+}
+
+#[test]
+fn presents_one_frame_per_vblank_with_the_changed_area() {
+    let mut vm = VM::boot();
+    vm.load_sprites();
+    vm.set_instructions_per_frame(100);
+    // LD V0, 10; LD V1, 4; LD F, V0; DRW V0, V1, 5; DRW V1, V0, 5; JP 0x20A
+    vm.load_rom(&mut Cursor::new(vec![0x60, 0x0A, 0x61, 0x04, 0xF0, 0x29, 0xD0, 0x15,
+                                      0xD1, 0x05, 0x12, 0x0A]))
+        .unwrap();
+    let (sender, receiver) = display::latest_frame();
+    vm.set_display(Box::new(sender));
+
+    vm.run_for(1).unwrap();
+    let frame = receiver.take().unwrap();
+    // Both sprites, at (10, 4) and (4, 10)
+    assert_eq!(Rect::new(4, 4, 14, 11), frame.dirty);
+    assert_eq!(vm.gfx, frame.pixels);
+
+    // Nothing changes anymore
+    vm.run_for(1).unwrap();
+    assert_eq!(None, receiver.take());
+}
+
+#[test]
+fn saves_screenshots_of_the_current_screen() {
+    let mut vm = VM::boot();
+    vm.gfx[1] = 1;
+    let screenshot = Screenshot { format: ImageFormat::Ppm, scale: 1, ..Screenshot::default() };
+
+    let mut image = vec![];
+    vm.save_screenshot(&mut image, &screenshot).unwrap();
+
+    let image = String::from_utf8(image).unwrap();
+    assert!(image.starts_with("P3\n64 32\n255\n0 0 0\n255 255 255\n0 0 0\n"));
+    assert_eq!(3 + DISPLAY_PIXELS, image.lines().count());
+}
+
+#[test]
+fn the_host_waits_for_the_next_frame_even_when_busy() {
+    let mut vm = VM::boot();
+    vm.set_clock(Box::new(RealtimeClock::new()));
+    // ADD V0, 1; JP 0x200
+    vm.load_rom(&mut Cursor::new(vec![0x70, 0x01, 0x12, 0x00])).unwrap();
+
+    vm.cycle().unwrap();
+
+    assert!(!vm.idle);
+    assert!(vm.next_frame_in().unwrap() <= Duration::from_millis(17));
+}
+
+#[test]
+fn tells_the_display_about_vblanks_without_changes() {
+    let recording = Arc::new(Mutex::new(GifRecorder::new(vec![], Recording::default())));
+    let mut vm = VmBuilder::new()
+        .display(Box::new(recording.clone()))
+        .rom(vec![0x00, 0xE0, 0x71, 0x01, 0x12, 0x02]) // CLS; ADD V1, 1; JP 0x202
+        .build()
+        .unwrap();
+
+    vm.run_for(59).unwrap();
+
+    // The first screen and every frame after it
+    let mut recording = recording.lock().unwrap();
+    assert_eq!(1.0, recording.duration());
+    assert!(recording.finish().is_ok());
+}
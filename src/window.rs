@@ -0,0 +1,231 @@
+// Window frontend, drawn and read with SDL. Only built with the `sdl`
+// feature, which is on by default.
+
+use johnny_eight::display::{self, Disconnected, DisplaySink, Frame, FrameSender};
+use johnny_eight::keypad::Key;
+use johnny_eight::specs;
+use johnny_eight::terminal::Bell;
+use johnny_eight::vm::{VmBuilder, State};
+
+use sdl2;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Point;
+use sdl2::render::Renderer;
+
+use std::io;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+
+use {Captures, Gif};
+
+pub fn run_in_window(builder: VmBuilder, captures: &Captures) {
+    // Window initialization
+    let ctx = sdl2::init().unwrap();
+    let video_ctx = ctx.video().unwrap();
+    let width = (specs::DISPLAY_WIDTH * specs::DISPLAY_SCALE) as u32;
+    let height = (specs::DISPLAY_HEIGHT * specs::DISPLAY_SCALE) as u32;
+    let window = video_ctx.window("Johnny Eight", width, height)
+        .position_centered()
+        .opengl()
+        .build()
+        .unwrap();
+
+    let mut renderer = window.renderer().build().unwrap();
+    let _ = renderer.set_logical_size(specs::DISPLAY_WIDTH as u32, specs::DISPLAY_HEIGHT as u32);
+
+    // Paint screen black
+    renderer.set_draw_color(Color::RGB(0, 0, 0));
+    renderer.clear();
+
+    // Display the black screen.
+    renderer.present();
+
+    let mut screen = SdlDisplay { renderer, size: (0, 0) };
+    let (sender, frames) = display::latest_frame();
+    let recording = Arc::new(Mutex::new(None));
+    let display = WindowSink {
+        frames: sender,
+        recording: recording.clone(),
+    };
+    // There is no audio device yet, the terminal it started from beeps
+    let builder = builder.display(Box::new(display)).audio(Box::new(Bell::new(io::stdout())));
+    let mut vm = match builder.build() {
+        Ok(vm) => vm,
+        Err(err) => {
+            println!("{}", err);
+            exit(1);
+        }
+    };
+
+    let mut events = ctx.event_pump().unwrap();
+
+    // loop until we receive a QuitEvent
+    'event: loop {
+        // Nothing to do until the next frame or the next event
+        let mut pending = vec![];
+        if let Some(timeout) = vm.next_frame_in() {
+            let timeout = timeout.as_millis() as u32;
+            if let Some(event) = events.wait_event_timeout(timeout) {
+                pending.push(event);
+            }
+        }
+        pending.extend(events.poll_iter());
+
+        for event in pending {
+            match event {
+                Event::Quit { .. } |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'event,
+
+                // Hold to rewind
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => vm.set_rewinding(true),
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => vm.set_rewinding(false),
+
+                Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } => {
+                    match captures.save_screenshot(&vm) {
+                        Ok(path) => println!("Saved {}", path.display()),
+                        Err(err) => println!("Can't save the screenshot: {}", err),
+                    }
+                }
+
+                // Starts and stops recording
+                Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                    let mut recording = recording.lock().unwrap();
+                    match recording.take() {
+                        Some((gif, path)) => stop_recording(gif, path),
+                        None => {
+                            match captures.start_recording() {
+                                Ok((mut gif, path)) => {
+                                    println!("Recording to {}", path.display());
+                                    // It starts with the screen as it is now
+                                    let _ = gif.present(&vm.frame());
+                                    *recording = Some((gif, path));
+                                }
+                                Err(err) => println!("Can't start recording: {}", err),
+                            }
+                        }
+                    }
+                }
+
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some(key) = keypad_key(keycode) {
+                        vm.key_down(key);
+                    }
+                }
+
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(key) = keypad_key(keycode) {
+                        vm.key_up(key);
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        if let Err(err) = vm.cycle() {
+            println!("{}", err);
+            exit(1);
+        }
+        if let Some(frame) = frames.take() {
+            screen.draw(&frame);
+        }
+
+        if vm.state() == State::Exited {
+            break 'event;
+        }
+    }
+
+    let active = recording.lock().unwrap().take();
+    if let Some((gif, path)) = active {
+        stop_recording(gif, path);
+    }
+}
+
+fn stop_recording(mut gif: Gif, path: PathBuf) {
+    match gif.finish() {
+        Ok(()) => println!("Saved {}, {:.1} seconds", path.display(), gif.duration()),
+        Err(err) => println!("Can't save the recording: {}", err),
+    }
+}
+
+// The renderer can't leave the thread it was made on, but the VM can, so
+// the VM gets this sink: it hands the frames to the recording if there is
+// one and sends them to the loop to be drawn.
+struct WindowSink {
+    frames: FrameSender,
+    recording: Arc<Mutex<Option<(Gif, PathBuf)>>>,
+}
+
+impl DisplaySink for WindowSink {
+    fn present(&mut self, frame: &Frame) -> Result<(), Disconnected> {
+        if let Some((ref mut gif, _)) = *self.recording.lock().map_err(|_| Disconnected)? {
+            gif.present(frame)?;
+        }
+
+        self.frames.present(frame)
+    }
+
+    fn repeat(&mut self) {
+        if let Ok(mut recording) = self.recording.lock() {
+            if let Some((ref mut gif, _)) = *recording {
+                gif.repeat();
+            }
+        }
+    }
+}
+
+// Draws the frames on the window, only the area that changed.
+struct SdlDisplay {
+    renderer: Renderer<'static>,
+    size: (usize, usize),
+}
+
+impl SdlDisplay {
+    fn draw(&mut self, frame: &Frame) {
+        if self.size != (frame.width, frame.height) {
+            self.size = (frame.width, frame.height);
+            let _ = self.renderer.set_logical_size(frame.width as u32, frame.height as u32);
+        }
+
+        let area = frame.dirty;
+        for y in area.y..area.y + area.height {
+            for x in area.x..area.x + area.width {
+                let color = display::color(frame.pixel(x, y));
+                self.renderer.set_draw_color(Color::RGB(color.r, color.g, color.b));
+                let _ = self.renderer.draw_point(Point::new(x as i32, y as i32));
+            }
+        }
+        self.renderer.present();
+    }
+}
+
+// Maps the left side of a QWERTY keyboard to the hex keypad:
+//
+//   1 2 3 4      1 2 3 C
+//   Q W E R  =>  4 5 6 D
+//   A S D F      7 8 9 E
+//   Z X C V      A 0 B F
+fn keypad_key(keycode: Keycode) -> Option<Key> {
+    match keycode {
+        Keycode::Num1 => Some(Key::Num1),
+        Keycode::Num2 => Some(Key::Num2),
+        Keycode::Num3 => Some(Key::Num3),
+        Keycode::Num4 => Some(Key::C),
+        Keycode::Q => Some(Key::Num4),
+        Keycode::W => Some(Key::Num5),
+        Keycode::E => Some(Key::Num6),
+        Keycode::R => Some(Key::D),
+        Keycode::A => Some(Key::Num7),
+        Keycode::S => Some(Key::Num8),
+        Keycode::D => Some(Key::Num9),
+        Keycode::F => Some(Key::E),
+        Keycode::Z => Some(Key::A),
+        Keycode::X => Some(Key::Num0),
+        Keycode::C => Some(Key::B),
+        Keycode::V => Some(Key::F),
+        _ => None,
+    }
+}